@@ -0,0 +1,144 @@
+//! Write latency and read throughput of the item store against the size of
+//! the catalog.
+//!
+//! Run with `cargo bench --bench store`. Each write appends to the operation
+//! log, so creates and updates should take about as long with 60k items as
+//! with 1k; only compaction, which rewrites the snapshot, grows with the
+//! catalog. Reads are served from memory, and are compared with reading and
+//! parsing the data file on every read.
+
+use playasia::config::DatabaseSettings;
+use playasia::routes::Item;
+use playasia::schema;
+use playasia::store::ItemStore;
+use std::time::{Duration, Instant};
+
+/// Item IDs are `u16`, which caps the largest catalog.
+const SIZES: [usize; 3] = [1_000, 10_000, 60_000];
+const WRITES: usize = 200;
+const READ_TIME: Duration = Duration::from_millis(500);
+
+/// A store in its own temporary directory, seeded with `size` items and
+/// never compacted on its own.
+fn seeded(size: usize) -> (ItemStore, DatabaseSettings) {
+    let dir = std::env::temp_dir().join(format!("playasia_bench_{}", size));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create store directory.");
+
+    let items: Vec<Item> = (1..=size as u16)
+        .map(|id| Item::new(id, format!("Item {}", id)))
+        .collect();
+    let data = schema::encode(&items.iter().collect::<Vec<_>>(), size as u16)
+        .expect("Failed to encode items.");
+    std::fs::write(dir.join("data.json"), data).expect("Failed to write data file.");
+
+    let settings = DatabaseSettings {
+        name: dir.join("data.json").to_string_lossy().into_owned(),
+        revisions_file: dir.join("revisions.jsonl").to_string_lossy().into_owned(),
+        compact_after: usize::MAX,
+        ..DatabaseSettings::default()
+    };
+    let store = ItemStore::new(&settings);
+    store.check().expect("Failed to load the store.");
+    (store, settings)
+}
+
+fn mean(f: impl Fn(usize)) -> Duration {
+    let started = Instant::now();
+    (0..WRITES).for_each(f);
+    started.elapsed() / WRITES as u32
+}
+
+/// Calls per second of `f`, over about `READ_TIME`.
+fn throughput(mut f: impl FnMut(usize)) -> f64 {
+    let started = Instant::now();
+    let mut calls = 0;
+    while started.elapsed() < READ_TIME {
+        f(calls);
+        calls += 1;
+    }
+    calls as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Reads the data file the way every read did before the items were
+/// cached.
+fn read_file(settings: &DatabaseSettings) -> Vec<Item> {
+    let data = std::fs::read(&settings.name).unwrap();
+    schema::decode(&data).unwrap().items
+}
+
+fn remove(settings: &DatabaseSettings) {
+    let _ = std::fs::remove_dir_all(
+        std::path::Path::new(&settings.name)
+            .parent()
+            .expect("Store directory"),
+    );
+}
+
+fn writes() {
+    println!(
+        "{:>8}  {:>12}  {:>12}  {:>12}",
+        "items", "create", "update", "compact"
+    );
+
+    for size in SIZES {
+        let (store, settings) = seeded(size);
+
+        let create = mean(|i| {
+            store.create(format!("New item {}", i)).unwrap();
+        });
+        let update = mean(|i| {
+            store
+                .update((i + 1) as u16, format!("Renamed item {}", i))
+                .unwrap();
+        });
+
+        let started = Instant::now();
+        store.flush().unwrap();
+        let compact = started.elapsed();
+
+        println!(
+            "{:>8}  {:>12?}  {:>12?}  {:>12?}",
+            size, create, update, compact
+        );
+        remove(&settings);
+    }
+}
+
+fn reads() {
+    println!(
+        "{:>8}  {:>14}  {:>14}  {:>14}  {:>14}",
+        "items", "get (file)", "get (cached)", "list (file)", "list (cached)"
+    );
+
+    for size in SIZES {
+        let (store, settings) = seeded(size);
+        let id = |i: usize| (i % size + 1) as u16;
+
+        let file_get = throughput(|i| {
+            let items = read_file(&settings);
+            assert!(items.iter().any(|item| item.id == id(i)));
+        });
+        let cached_get = throughput(|i| {
+            store.get(id(i)).unwrap();
+        });
+        let file_list = throughput(|_| {
+            read_file(&settings);
+        });
+        let cached_list = throughput(|_| {
+            store.all().unwrap();
+        });
+
+        println!(
+            "{:>8}  {:>12.0}/s  {:>12.0}/s  {:>12.0}/s  {:>12.0}/s",
+            size, file_get, cached_get, file_list, cached_list
+        );
+        remove(&settings);
+    }
+}
+
+fn main() {
+    writes();
+    println!();
+    reads();
+}
@@ -0,0 +1,238 @@
+use crate::auth::{now, Identity};
+use crate::metrics::Metrics;
+use crate::middleware::RequestId;
+use crate::routes::Item;
+use crate::store::{unblock, StoreError};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    /// The item was taken out of the trash or set back to an earlier
+    /// revision.
+    Restore,
+    /// The item was removed from the trash for good.
+    Purge,
+}
+
+/// One change made to an item: who made it, in which request, and the item
+/// before and after. `before` is absent for creations, and for restores of
+/// purged items, and `after` for deletions and purges.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub sub: String,
+    pub request_id: String,
+    pub action: AuditAction,
+    pub item_id: u16,
+    pub before: Option<Item>,
+    pub after: Option<Item>,
+}
+
+/// The `sub` of entries for changes the app makes on its own, such as
+/// purging items past the trash retention period.
+pub const SYSTEM_SUB: &str = "system";
+
+impl AuditEntry {
+    pub fn new(
+        identity: &Identity,
+        request_id: &RequestId,
+        action: AuditAction,
+        before: Option<Item>,
+        after: Option<Item>,
+    ) -> Self {
+        Self::by(&identity.sub, &request_id.0, action, before, after)
+    }
+
+    /// An entry for a change made by the app itself, as part of the
+    /// background run `run_id`, which takes the place of the request ID.
+    pub fn system(
+        run_id: &str,
+        action: AuditAction,
+        before: Option<Item>,
+        after: Option<Item>,
+    ) -> Self {
+        Self::by(SYSTEM_SUB, run_id, action, before, after)
+    }
+
+    fn by(
+        sub: &str,
+        request_id: &str,
+        action: AuditAction,
+        before: Option<Item>,
+        after: Option<Item>,
+    ) -> Self {
+        let item_id = after
+            .as_ref()
+            .or(before.as_ref())
+            .map(|item| item.id)
+            .unwrap_or_default();
+
+        Self {
+            timestamp: now(),
+            sub: sub.to_string(),
+            request_id: request_id.to_string(),
+            action,
+            item_id,
+            before,
+            after,
+        }
+    }
+}
+
+/// Which entries to return from the audit log. Every criterion that is set
+/// must match; `from` and `to` are inclusive Unix timestamps.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub item_id: Option<u16>,
+    pub sub: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.item_id.is_none_or(|id| entry.item_id == id)
+            && self.sub.as_ref().is_none_or(|sub| &entry.sub == sub)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+/// Audit entries stored one JSON object per line. Entries are only ever
+/// appended; nothing in the app rewrites the file, except to cut off the
+/// last line when an append was cut short.
+///
+/// A change is stored before its entry is recorded, so a failure to record
+/// one does not undo or fail the change. It is logged, counted as an
+/// `audit` storage error and fails readiness until an entry is recorded
+/// again.
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+    metrics: Option<Metrics>,
+    /// Why the last entry could not be recorded, if it could not.
+    failure: Mutex<Option<String>>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+            metrics: None,
+            failure: Mutex::new(None),
+        }
+    }
+
+    /// Records the time taken by each append, and its failures, in
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Runs `f` against the log on the blocking thread pool.
+    pub async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&AuditLog) -> T + Send + 'static,
+    ) -> T {
+        let audit = self.clone();
+        unblock(move || f(&audit)).await
+    }
+
+    /// Appends an entry and waits for it to reach the disk.
+    pub fn record(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        let started = Instant::now();
+        let result = self.append(entry);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_storage("audit", started.elapsed(), result.is_ok());
+        }
+        *self.failure.lock().unwrap_or_else(|err| err.into_inner()) =
+            result.as_ref().err().map(|err| err.to_string());
+        result
+    }
+
+    /// Why the last entry could not be recorded, or `None` if it was.
+    pub fn failure(&self) -> Option<String> {
+        self.failure
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let _lock = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&self.path)?;
+
+        // Cut off a line left without its newline by an append that was cut
+        // short, so that it does not run into this one.
+        let mut end = file.seek(SeekFrom::End(0))?;
+        if end > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::Start(end - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                let mut data = Vec::new();
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut data)?;
+                end = complete(&data) as u64;
+                file.set_len(end)?;
+                tracing::warn!(
+                    audit_file = %self.path.display(),
+                    "cut off an audit entry that was only partly written"
+                );
+            }
+            file.seek(SeekFrom::Start(end))?;
+        }
+
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// The entries matching `filter`, oldest first. A last line that was
+    /// only partly written is left out.
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, StoreError> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut entries = Vec::new();
+        let lines = data[..complete(data.as_bytes())].lines();
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let entry: AuditEntry = serde_json::from_str(line)?;
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The length of `data` up to and including its last newline.
+fn complete(data: &[u8]) -> usize {
+    data.iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1)
+}
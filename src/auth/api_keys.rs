@@ -0,0 +1,180 @@
+use crate::auth::now;
+use crate::oplog::write_atomically;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+const KEY_PREFIX: &str = "pa_";
+
+/// A stored API key. Only the SHA-256 hash of the secret is kept; the
+/// plaintext is returned once, when the key is created.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub key_hash: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked: bool,
+}
+
+impl ApiKey {
+    pub fn is_active(&self, at: u64) -> bool {
+        !self.revoked && self.expires_at.is_none_or(|expires_at| expires_at > at)
+    }
+
+    /// Copy of the key that is safe to show to clients.
+    pub fn redacted(&self) -> Self {
+        Self {
+            key_hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ApiKeyStore {
+    path: Option<PathBuf>,
+    keys: Arc<RwLock<Vec<ApiKey>>>,
+}
+
+impl ApiKeyStore {
+    /// A store that lives only as long as the process.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Loads the keys persisted at `path`, starting empty if it does not exist.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let path = path.into();
+        let keys = match fs::read_to_string(&path) {
+            Ok(data) if !data.trim().is_empty() => serde_json::from_str(&data)?,
+            Ok(_) => Vec::new(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// Verifies that the persisted keys can still be read, returning the
+    /// number of keys that are currently active.
+    pub fn check(&self) -> Result<usize, Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            match fs::read_to_string(path) {
+                Ok(data) if !data.trim().is_empty() => {
+                    serde_json::from_str::<Vec<ApiKey>>(&data)?;
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let at = now();
+        Ok(self
+            .keys
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter(|key| key.is_active(at))
+            .count())
+    }
+
+    /// Creates a key and returns its metadata along with the plaintext secret.
+    /// A key that cannot be saved is not created.
+    pub fn create(
+        &self,
+        name: &str,
+        scopes: Vec<String>,
+        expires_at: Option<u64>,
+    ) -> Result<(ApiKey, String), Box<dyn Error>> {
+        let secret = format!(
+            "{}{}{}",
+            KEY_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+
+        let key = ApiKey {
+            id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+            name: name.to_string(),
+            scopes,
+            key_hash: hash_key(&secret),
+            created_at: now(),
+            expires_at,
+            revoked: false,
+        };
+
+        let mut keys = self.keys.write().unwrap_or_else(|err| err.into_inner());
+        keys.push(key.clone());
+        if let Err(err) = self.persist(&keys) {
+            keys.pop();
+            return Err(err);
+        }
+
+        Ok((key.redacted(), secret))
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .map(ApiKey::redacted)
+            .collect()
+    }
+
+    /// Marks a key as revoked. Returns `Ok(None)` if no key has that ID. A
+    /// revocation that cannot be saved is undone.
+    pub fn revoke(&self, id: &str) -> Result<Option<ApiKey>, Box<dyn Error>> {
+        let mut keys = self.keys.write().unwrap_or_else(|err| err.into_inner());
+        let Some(key) = keys.iter_mut().find(|key| key.id == id) else {
+            return Ok(None);
+        };
+
+        let was_revoked = std::mem::replace(&mut key.revoked, true);
+        let revoked = key.redacted();
+        if let Err(err) = self.persist(&keys) {
+            if let Some(key) = keys.iter_mut().find(|key| key.id == id) {
+                key.revoked = was_revoked;
+            }
+            return Err(err);
+        }
+
+        Ok(Some(revoked))
+    }
+
+    /// Returns the active key matching the presented secret, if any.
+    pub fn verify(&self, secret: &str) -> Option<ApiKey> {
+        let hash = hash_key(secret);
+        let at = now();
+
+        self.keys
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .find(|key| key.key_hash == hash && key.is_active(at))
+            .cloned()
+    }
+
+    fn persist(&self, keys: &[ApiKey]) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            write_atomically(path, &serde_json::to_vec_pretty(keys)?)?;
+        }
+        Ok(())
+    }
+}
+
+fn hash_key(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
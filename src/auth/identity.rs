@@ -0,0 +1,48 @@
+use serde::Serialize;
+
+/// Scope required to create, edit or delete items.
+pub const SCOPE_ITEMS_WRITE: &str = "items:write";
+/// Scope required for the `/admin` endpoints.
+pub const SCOPE_ADMIN: &str = "admin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Jwt,
+    ApiKey,
+    Signature,
+    ClientCertificate,
+}
+
+/// The authenticated caller of a request, whatever credential it used.
+/// Inserted into the request extensions by the auth middleware.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub sub: String,
+    pub scopes: Vec<String>,
+    pub method: AuthMethod,
+}
+
+impl Identity {
+    /// JWT users may always write items; their `roles` claim grants any
+    /// further scopes such as `admin`.
+    pub fn from_jwt(sub: &str, roles: &[String]) -> Self {
+        let mut scopes = vec![SCOPE_ITEMS_WRITE.to_string()];
+        scopes.extend(
+            roles
+                .iter()
+                .filter(|role| *role != SCOPE_ITEMS_WRITE)
+                .cloned(),
+        );
+
+        Self {
+            sub: sub.to_string(),
+            scopes,
+            method: AuthMethod::Jwt,
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
@@ -0,0 +1,9 @@
+pub mod api_keys;
+pub mod identity;
+pub mod signing;
+pub mod tokens;
+
+pub use api_keys::*;
+pub use identity::*;
+pub use signing::*;
+pub use tokens::*;
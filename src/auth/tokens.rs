@@ -0,0 +1,260 @@
+use crate::config::AuthSettings;
+use crate::errors::{ApiError, JwtErrorKind};
+use crate::oplog::write_atomically;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const REFRESH_TOKEN_TYPE: &str = "refresh";
+
+/// Claims carried by access tokens.
+///
+/// `jti` is optional so that long-lived tokens minted before revocation
+/// support existed keep working; such tokens simply cannot be revoked.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+}
+
+/// Claims carried by refresh tokens. Every refresh token belongs to a
+/// family (`fam`) that is shared by all tokens obtained through rotation.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RefreshClaims {
+    pub sub: String,
+    pub exp: usize,
+    pub jti: String,
+    pub fam: String,
+    pub typ: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Issues a fresh access/refresh token pair. Passing `family` continues an
+/// existing refresh token family, otherwise a new one is started.
+pub fn issue_token_pair(
+    settings: &AuthSettings,
+    sub: &str,
+    roles: &[String],
+    family: Option<String>,
+) -> Result<TokenPair, jsonwebtoken::errors::Error> {
+    let issued_at = now();
+    let key = EncodingKey::from_secret(settings.secret.as_bytes());
+
+    let refresh_claims = RefreshClaims {
+        sub: sub.to_string(),
+        exp: (issued_at + settings.refresh_token_ttl) as usize,
+        jti: Uuid::new_v4().to_string(),
+        fam: family.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        typ: REFRESH_TOKEN_TYPE.to_string(),
+        roles: roles.to_vec(),
+    };
+
+    Ok(TokenPair {
+        access_token: issue_access_token(settings, sub, roles, settings.access_token_ttl)?,
+        refresh_token: encode(&Header::default(), &refresh_claims, &key)?,
+        token_type: "Bearer",
+        expires_in: settings.access_token_ttl,
+    })
+}
+
+/// Issues a standalone access token valid for `ttl` seconds.
+pub fn issue_access_token(
+    settings: &AuthSettings,
+    sub: &str,
+    roles: &[String],
+    ttl: u64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: sub.to_string(),
+        exp: now().saturating_add(ttl) as usize,
+        jti: Some(Uuid::new_v4().to_string()),
+        typ: None,
+        roles: roles.to_vec(),
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(settings.secret.as_bytes()),
+    )
+}
+
+pub fn decode_access_token(token: &str, secret: &str) -> Result<Claims, JwtErrorKind> {
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+    .map_err(jwt_error_kind)?;
+
+    if claims.typ.as_deref() == Some(REFRESH_TOKEN_TYPE) {
+        return Err(JwtErrorKind::Invalid);
+    }
+
+    Ok(claims)
+}
+
+pub fn decode_refresh_token(token: &str, secret: &str) -> Result<RefreshClaims, JwtErrorKind> {
+    let claims = decode::<RefreshClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+    .map_err(jwt_error_kind)?;
+
+    if claims.typ != REFRESH_TOKEN_TYPE {
+        return Err(JwtErrorKind::Invalid);
+    }
+
+    Ok(claims)
+}
+
+fn jwt_error_kind(err: jsonwebtoken::errors::Error) -> JwtErrorKind {
+    match err.kind() {
+        jsonwebtoken::errors::ErrorKind::ExpiredSignature => JwtErrorKind::Expired,
+        _ => JwtErrorKind::Invalid,
+    }
+}
+
+/// Server-side token state: the `jti` denylist for access tokens and the
+/// bookkeeping needed for refresh token rotation and reuse detection.
+///
+/// Entries are kept until the token they refer to would have expired anyway.
+/// A store loaded from a file writes every change back to it, so that
+/// revoked tokens stay revoked across restarts.
+#[derive(Clone, Default)]
+pub struct TokenStore {
+    path: Option<PathBuf>,
+    inner: Arc<Mutex<TokenStoreInner>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct TokenStoreInner {
+    revoked: HashMap<String, u64>,
+    used_refresh: HashMap<String, u64>,
+    revoked_families: HashMap<String, u64>,
+}
+
+impl TokenStore {
+    /// A store that lives only as long as the process.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the state persisted at `path`, starting empty if it does not
+    /// exist.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let path = path.into();
+        let mut inner: TokenStoreInner = match fs::read_to_string(&path) {
+            Ok(data) if !data.trim().is_empty() => serde_json::from_str(&data)?,
+            Ok(_) => TokenStoreInner::default(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => TokenStoreInner::default(),
+            Err(err) => return Err(err.into()),
+        };
+        inner.prune(now());
+
+        Ok(Self {
+            path: Some(path),
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    pub fn revoke(&self, jti: &str, exp: u64) -> Result<(), Box<dyn Error>> {
+        let mut inner = self.lock();
+        inner.prune(now());
+        inner.revoked.insert(jti.to_string(), exp);
+        self.persist(&inner)
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.lock().revoked.contains_key(jti)
+    }
+
+    pub fn revoke_family(&self, family: &str, exp: u64) -> Result<(), Box<dyn Error>> {
+        let mut inner = self.lock();
+        inner.prune(now());
+        inner.revoked_families.insert(family.to_string(), exp);
+        self.persist(&inner)
+    }
+
+    /// Consumes a refresh token. Presenting an already used token is treated
+    /// as theft: the whole family is revoked until `family_exp` (the latest
+    /// moment any token of the family can expire) and `Revoked` is returned.
+    /// Fails without issuing anything if the change cannot be persisted, as
+    /// the token could otherwise be used again after a restart.
+    pub fn consume_refresh(&self, claims: &RefreshClaims, family_exp: u64) -> Result<(), ApiError> {
+        let mut inner = self.lock();
+        inner.prune(now());
+
+        if inner.revoked_families.contains_key(&claims.fam) {
+            return Err(JwtErrorKind::Revoked.into());
+        }
+
+        let result = if inner.used_refresh.contains_key(&claims.jti) {
+            inner
+                .revoked_families
+                .insert(claims.fam.clone(), family_exp);
+            Err(JwtErrorKind::Revoked.into())
+        } else {
+            inner
+                .used_refresh
+                .insert(claims.jti.clone(), claims.exp as u64);
+            Ok(())
+        };
+
+        self.persist(&inner).map_err(|err| {
+            ApiError::Storage(format!("Failed to record the refresh token: {}", err))
+        })?;
+        result
+    }
+
+    fn persist(&self, inner: &TokenStoreInner) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            write_atomically(path, &serde_json::to_vec(inner)?)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TokenStoreInner> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl TokenStoreInner {
+    fn prune(&mut self, now: u64) {
+        self.revoked.retain(|_, exp| *exp > now);
+        self.used_refresh.retain(|_, exp| *exp > now);
+        self.revoked_families.retain(|_, exp| *exp > now);
+    }
+}
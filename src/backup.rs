@@ -0,0 +1,156 @@
+use crate::schema;
+use crate::store::{unblock, Change, ItemStore, StoreError};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A snapshot of the catalog in the backup directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    /// The file name, which also identifies the backup when restoring it.
+    pub name: String,
+    /// When the backup was taken, as a Unix timestamp.
+    pub created_at: u64,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The number of items, including those in the trash.
+    pub items: usize,
+    /// Hex SHA-256 of the file.
+    pub sha256: String,
+}
+
+/// A backup restored into the item store, with the `(before, after)` pair
+/// of each item that changed.
+pub struct Restored {
+    pub backup: BackupInfo,
+    pub changes: Vec<Change>,
+}
+
+/// Snapshots of the item store taken while the server runs, kept as data
+/// files named `backup-<milliseconds since the epoch>.json` in one
+/// directory. Unlike the copies kept before each compaction, they are never
+/// rotated away.
+pub struct Backups {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Backups {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Runs `f` against the backups and `store` on the blocking thread pool.
+    pub async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        store: &Arc<ItemStore>,
+        f: impl FnOnce(&Backups, &ItemStore) -> T + Send + 'static,
+    ) -> T {
+        let (backups, store) = (self.clone(), store.clone());
+        unblock(move || f(&backups, &store)).await
+    }
+
+    /// Writes a copy of every item in `store` to a new backup.
+    pub fn create(&self, store: &ItemStore) -> Result<BackupInfo, StoreError> {
+        let _lock = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        fs::create_dir_all(&self.dir)?;
+
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        while self.dir.join(name(millis)).exists() {
+            millis += 1;
+        }
+
+        let name = name(millis);
+        let items = store.write_copy(&self.dir.join(&name))?;
+        let data = fs::read(self.dir.join(&name))?;
+        Ok(info(name, millis, &data, items))
+    }
+
+    /// Every backup that can be read, oldest first. Files that cannot are
+    /// logged and left out.
+    pub fn list(&self) -> Result<Vec<BackupInfo>, StoreError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if parse(&name).is_none() {
+                continue;
+            }
+            match self.read(&name) {
+                Ok(Some((backup, _))) => backups.push(backup),
+                Ok(None) => {}
+                Err(err) => tracing::warn!(backup = %name, "unreadable backup: {}", err),
+            }
+        }
+
+        backups.sort_by_key(|backup| parse(&backup.name));
+        Ok(backups)
+    }
+
+    /// Replaces the items in `store` with those of the backup `name`, or
+    /// returns `None` if there is no such backup. Backups are neither
+    /// created nor restored meanwhile.
+    pub fn restore(&self, name: &str, store: &ItemStore) -> Result<Option<Restored>, StoreError> {
+        let _lock = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let Some((backup, data)) = self.read(name)? else {
+            return Ok(None);
+        };
+        let changes = store.replace(&data)?;
+
+        Ok(Some(Restored { backup, changes }))
+    }
+
+    /// The backup `name` and its contents, or `None` if there is no such
+    /// backup. Only names of the form given by `create` are looked up, so
+    /// that a name cannot reach outside the directory.
+    fn read(&self, name: &str) -> Result<Option<(BackupInfo, Vec<u8>)>, StoreError> {
+        let Some(millis) = parse(name) else {
+            return Ok(None);
+        };
+        let data = match fs::read(self.dir.join(name)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let items = schema::decode(&data)?.items.len();
+        Ok(Some((info(name.to_string(), millis, &data, items), data)))
+    }
+}
+
+fn name(millis: u64) -> String {
+    format!("backup-{}.json", millis)
+}
+
+/// The time in the name of a backup, in milliseconds since the epoch.
+fn parse(name: &str) -> Option<u64> {
+    let millis = name.strip_prefix("backup-")?.strip_suffix(".json")?;
+    if millis.is_empty() || !millis.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    millis.parse().ok()
+}
+
+fn info(name: String, millis: u64, data: &[u8], items: usize) -> BackupInfo {
+    BackupInfo {
+        name,
+        created_at: millis / 1000,
+        size: data.len() as u64,
+        items,
+        sha256: hex::encode(Sha256::digest(data)),
+    }
+}
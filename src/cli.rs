@@ -0,0 +1,340 @@
+use crate::auth::{issue_access_token, issue_token_pair};
+use crate::config::{ConfigLoader, Settings};
+use crate::routes::RequestBody;
+use crate::schema;
+use crate::server;
+use crate::store::{ItemStore, MigrationPlan};
+use crate::validation::ValidationRules;
+use clap::{Args, Parser, Subcommand};
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "playasia", version, about = "Item catalogue API server")]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
+    /// Defaults to `serve`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options shared by every command, layered over the configuration files.
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    /// Base configuration file.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Configuration profile (dev, test or prod).
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Data file, overriding `database.name`.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub data: Option<PathBuf>,
+
+    /// Overrides a single setting, e.g. `--set auth.access_token_ttl=300`.
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_assignment)]
+    pub overrides: Vec<(String, String)>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the HTTP server.
+    Serve {
+        /// Port to listen on, overriding `application.port`.
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Validates the configuration and exits.
+    CheckConfig,
+    /// Prints an access token signed with the configured secret.
+    MintToken {
+        /// Subject of the token.
+        #[arg(long)]
+        sub: String,
+        /// Lifetime, in seconds or with an `s`, `m`, `h` or `d` suffix.
+        /// Defaults to `auth.access_token_ttl`.
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        exp: Option<u64>,
+        /// Comma-separated roles, e.g. `admin`.
+        #[arg(long, value_delimiter = ',')]
+        roles: Vec<String>,
+        /// Prints an access and refresh token pair as JSON, as returned by
+        /// `/auth/refresh`, instead. Both get their configured lifetimes.
+        #[arg(long, conflicts_with = "exp")]
+        refresh: bool,
+    },
+    /// Adds the items in a JSON array file to the data file.
+    ///
+    /// Items are validated like API requests and get new IDs. Nothing is
+    /// imported if any of them is rejected.
+    Import { file: PathBuf },
+    /// Writes all items as a JSON array to a file, or to stdout.
+    Export { file: Option<PathBuf> },
+    /// Upgrades the data file to the current format.
+    Migrate {
+        /// Reports what would change without writing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl ConfigArgs {
+    pub fn loader(&self) -> ConfigLoader {
+        let mut loader = ConfigLoader::new();
+
+        if let Some(path) = &self.config {
+            loader = loader.path(path);
+        }
+        if let Some(profile) = &self.profile {
+            loader = loader.profile(profile);
+        }
+        for (key, value) in &self.overrides {
+            loader = loader.set(key, value);
+        }
+        if let Some(data) = &self.data {
+            loader = loader.set("database.name", data.to_string_lossy());
+        }
+
+        loader
+    }
+}
+
+/// Runs the command, writing its output to `out`.
+pub fn execute(cli: Cli, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let command = cli.command.unwrap_or(Command::Serve { port: None });
+
+    let mut loader = cli.config.loader();
+    if let Command::Serve { port: Some(port) } = &command {
+        loader = loader.set("application.port", port.to_string());
+    }
+    let settings = loader
+        .load()
+        .map_err(|err| format!("failed to read config: {}", err))?;
+
+    match command {
+        Command::Serve { .. } => server::run(settings, Some(loader))?,
+        Command::CheckConfig => check_config(&settings, out)?,
+        Command::MintToken {
+            sub,
+            exp,
+            roles,
+            refresh: false,
+        } => {
+            let ttl = exp.unwrap_or(settings.auth.access_token_ttl);
+            let token = issue_access_token(&settings.auth, &sub, &roles, ttl)?;
+            writeln!(out, "{}", token)?;
+        }
+        Command::MintToken {
+            sub,
+            roles,
+            refresh: true,
+            ..
+        } => {
+            let pair = issue_token_pair(&settings.auth, &sub, &roles, None)?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&pair)?)?;
+        }
+        Command::Import { file } => import(&settings, &file, out)?,
+        Command::Export { file } => export(&settings, file, out)?,
+        Command::Migrate { dry_run } => migrate(&settings, dry_run, out)?,
+    }
+
+    Ok(())
+}
+
+fn check_config(settings: &Settings, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    writeln!(out, "Configuration is valid")?;
+    writeln!(
+        out,
+        "  listen: {}{}",
+        settings.application.connection_string(),
+        if settings.tls.enabled { " (TLS)" } else { "" }
+    )?;
+    writeln!(out, "  data file: {}", settings.database.name)?;
+    writeln!(out, "  api keys file: {}", settings.auth.api_keys_file)?;
+    writeln!(
+        out,
+        "  revoked tokens file: {}",
+        settings.auth.revoked_tokens_file
+    )?;
+    Ok(())
+}
+
+fn import(settings: &Settings, file: &PathBuf, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let entries: Vec<Value> = serde_json::from_str(&fs::read_to_string(file)?)
+        .map_err(|err| format!("{} is not a JSON array of items: {}", file.display(), err))?;
+
+    let rules = ValidationRules::new(settings.validation.clone())?;
+    let mut names = Vec::with_capacity(entries.len());
+    let mut problems = Vec::new();
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        match rules.validate::<RequestBody>(entry) {
+            Ok(body) => names.push(body.name),
+            Err(err) => problems.push(format!("item {}: {}", i, describe(&err))),
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(format!("nothing imported:\n  {}", problems.join("\n  ")).into());
+    }
+
+    let store = ItemStore::new(&settings.database);
+    let imported = store
+        .import(names)
+        .map_err(|err| format!("nothing imported: {}", err))?;
+    // Leave the data file complete, without a log to replay.
+    store.flush()?;
+    writeln!(out, "Imported {} items", imported.len())?;
+    Ok(())
+}
+
+fn export(
+    settings: &Settings,
+    file: Option<PathBuf>,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let items = ItemStore::new(&settings.database)
+        .all()
+        .map_err(|err| format!("failed to read {}: {}", settings.database.name, err))?;
+    let json = serde_json::to_string_pretty(&items)?;
+
+    match file {
+        Some(file) => fs::write(file, json)?,
+        None => writeln!(out, "{}", json)?,
+    }
+    Ok(())
+}
+
+fn migrate(settings: &Settings, dry_run: bool, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let store = ItemStore::new(&settings.database);
+    let plan = store.plan_migration()?;
+
+    if dry_run {
+        report(&settings.database.name, &plan, out)?;
+        writeln!(out, "Nothing written (dry run).")?;
+        return Ok(());
+    }
+
+    let count = store.migrate()?;
+    match plan.version {
+        Some(version) if version < schema::VERSION => writeln!(
+            out,
+            "Migrated {} from version {} to {} ({} items)",
+            settings.database.name,
+            version,
+            schema::VERSION,
+            count
+        )?,
+        _ => writeln!(out, "Migrated {} ({} items)", settings.database.name, count)?,
+    }
+    Ok(())
+}
+
+/// Describes a migration plan, one change per line.
+fn report(name: &str, plan: &MigrationPlan, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let Some(version) = plan.version else {
+        writeln!(
+            out,
+            "{} does not exist and would be created at version {}.",
+            name,
+            schema::VERSION
+        )?;
+        return Ok(());
+    };
+
+    if plan.steps.is_empty() && plan.logged_changes == 0 {
+        writeln!(
+            out,
+            "{} is up to date at version {} ({} items).",
+            name, version, plan.items
+        )?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{} is at version {}; the current version is {} ({} items).",
+        name,
+        version,
+        schema::VERSION,
+        plan.items
+    )?;
+    for step in &plan.steps {
+        match step.items_changed {
+            Some(changed) => writeln!(
+                out,
+                "  version {}: {} ({} items changed)",
+                step.version, step.description, changed
+            )?,
+            None => writeln!(out, "  version {}: {}", step.version, step.description)?,
+        }
+    }
+    if plan.logged_changes > 0 {
+        writeln!(
+            out,
+            "  {} logged item changes would be folded into the data file",
+            plan.logged_changes
+        )?;
+    }
+    Ok(())
+}
+
+/// One line naming every field error of a rejected item.
+fn describe(err: &crate::errors::ApiError) -> String {
+    let problem = err.problem(None);
+    if problem.errors.is_empty() {
+        return problem.detail;
+    }
+
+    problem
+        .errors
+        .iter()
+        .map(|error| format!("{} {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_assignment(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", value))
+}
+
+/// The longest duration accepted, a hundred years, so that adding it to the
+/// current time cannot overflow.
+const MAX_DURATION: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parses `90`, `90s`, `15m`, `12h` or `7d` into seconds, up to a hundred
+/// years.
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => value.split_at(at),
+        None => (value, "s"),
+    };
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}`, expected s, m, h or d", unit)),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|amount| *amount > 0)
+        .and_then(|amount| amount.checked_mul(multiplier))
+        .filter(|seconds| *seconds <= MAX_DURATION)
+        .ok_or_else(|| format!("invalid duration `{}`", value))
+}
@@ -1,25 +1,553 @@
-#[derive(serde::Deserialize)]
+use regex::Regex;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::{env, fmt};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
 pub struct Settings {
     pub application: ApplicationSettings,
     pub database: DatabaseSettings,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub tls: TlsSettings,
+    /// Overrides for the validation rules of request bodies, keyed by
+    /// request type (e.g. `item`) and then by field.
+    #[serde(default)]
+    pub validation: HashMap<String, HashMap<String, FieldRulesOverride>>,
+    #[serde(default)]
+    pub reload: ReloadSettings,
+    #[serde(default)]
+    pub logging: LoggingSettings,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct ApplicationSettings {
     pub port: u16,
     pub address: String,
+    /// How long, in seconds, in-flight requests may take to finish once a
+    /// shutdown was requested.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct DatabaseSettings {
     pub name: String,
+    /// Item fields whose values must be unique, compared case-insensitively
+    /// after normalization.
+    #[serde(default = "default_unique_fields")]
+    pub unique_fields: Vec<String>,
+    /// Append-only file recording every change made to items through the API.
+    #[serde(default = "default_audit_file")]
+    pub audit_file: String,
+    /// Append-only file keeping every revision of every item.
+    #[serde(default = "default_revisions_file")]
+    pub revisions_file: String,
+    /// How long, in seconds, deleted items stay in the trash before they are
+    /// purged. 0 keeps them until purged by hand.
+    #[serde(default = "default_trash_retention")]
+    pub trash_retention: u64,
+    /// How many item changes the operation log may hold before it is
+    /// compacted into the data file.
+    #[serde(default = "default_compact_after")]
+    pub compact_after: usize,
+    /// How long, in milliseconds, reads may go without checking the files
+    /// for changes made by someone else. `0` checks on every read.
+    #[serde(default = "default_refresh_interval_ms")]
+    pub refresh_interval_ms: u64,
+    /// How many copies of the data file to keep, taken before each rewrite.
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    /// Whether a corrupt data file found at startup is set aside and
+    /// restored from a backup. If not, the app starts but is not ready.
+    #[serde(default = "default_recover")]
+    pub recover: bool,
+    /// Directory holding the snapshots taken through `POST /admin/backup`.
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+}
+
+fn default_unique_fields() -> Vec<String> {
+    vec!["name".to_string()]
+}
+
+fn default_audit_file() -> String {
+    "audit.jsonl".to_string()
+}
+
+fn default_revisions_file() -> String {
+    "revisions.jsonl".to_string()
+}
+
+fn default_trash_retention() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_compact_after() -> usize {
+    1000
+}
+
+fn default_refresh_interval_ms() -> u64 {
+    1000
+}
+
+fn default_backups() -> usize {
+    3
+}
+
+fn default_recover() -> bool {
+    true
+}
+
+fn default_backup_dir() -> String {
+    "backups".to_string()
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    pub secret: String,
+    /// Lifetime of access tokens, in seconds.
+    pub access_token_ttl: u64,
+    /// Lifetime of refresh tokens, in seconds.
+    pub refresh_token_ttl: u64,
+    /// File where hashed API keys are persisted.
+    pub api_keys_file: String,
+    /// File where revoked and used token IDs are persisted until the tokens
+    /// expire.
+    pub revoked_tokens_file: String,
+    /// Shared keys accepted for HMAC request signatures.
+    pub signing_keys: Vec<SigningKey>,
+    /// How far, in seconds, a signature timestamp may drift from server time.
+    pub signature_window: u64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    /// PEM file with the server certificate chain.
+    pub cert: String,
+    /// PEM file with the server private key.
+    pub key: String,
+    /// PEM file with the CA certificates client certificates are verified
+    /// against. Client certificates are not requested when unset.
+    pub client_ca: Option<String>,
+    pub require_client_cert: bool,
+    /// Scopes granted to clients authenticated by their certificate.
+    pub client_scopes: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct LoggingSettings {
+    /// `EnvFilter` directive, e.g. `info` or `info,playasia=debug`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+/// Reloading of the configuration while the server runs.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ReloadSettings {
+    /// Whether configuration files are watched for changes. `SIGHUP`
+    /// triggers a reload either way.
+    pub watch: bool,
+    /// How often, in milliseconds, the files are checked for changes.
+    pub interval_ms: u64,
+}
+
+/// Partial validation rules; unset values keep the rule declared in code.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default, Debug)]
+pub struct FieldRulesOverride {
+    pub required: Option<bool>,
+    pub trim: Option<bool>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub pattern: Option<String>,
+    /// The message for a value that does not match the pattern.
+    pub pattern_message: Option<String>,
+}
+
+/// Environment variable naming the profile whose file is layered over the
+/// base configuration, e.g. `PLAYASIA_PROFILE=prod` for `config.prod.yaml`.
+pub const PROFILE_ENV: &str = "PLAYASIA_PROFILE";
+
+/// Prefix of environment variables overriding single settings, with `__`
+/// separating the path, e.g. `PLAYASIA__APPLICATION__PORT=9000`.
+pub const ENV_PREFIX: &str = "PLAYASIA";
+
+pub const PROFILES: &[&str] = &["dev", "test", "prod"];
+
+/// Settings holding lists, which are given as comma-separated values in
+/// environment variables and overrides.
+const LIST_KEYS: &[&str] = &["database.unique_fields", "tls.client_scopes"];
+
+/// Builds `Settings` from layered sources. Later layers win:
+///
+/// 1. the base file (`config.yaml` by default),
+/// 2. the profile file next to it (`config.<profile>.yaml`),
+/// 3. `PLAYASIA__*` environment variables,
+/// 4. explicit overrides, e.g. from command-line flags.
+///
+/// The result is validated, so errors name the offending key.
+#[derive(Clone)]
+pub struct ConfigLoader {
+    path: PathBuf,
+    profile: Option<String>,
+    env: Option<HashMap<String, String>>,
+    overrides: Vec<(String, String)>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self {
+            path: PathBuf::from("config"),
+            profile: env::var(PROFILE_ENV)
+                .ok()
+                .filter(|profile| !profile.is_empty()),
+            env: None,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// The base configuration file. The extension may be left out.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Selects a profile, taking precedence over `PLAYASIA_PROFILE`.
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Reads overrides from the given variables instead of the process
+    /// environment.
+    pub fn env(mut self, vars: HashMap<String, String>) -> Self {
+        self.env = Some(vars);
+        self
+    }
+
+    /// Overrides a single setting by its dotted key, e.g. `application.port`.
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn load(&self) -> Result<Settings, ConfigError> {
+        let mut builder =
+            config::Config::builder().add_source(config::File::from(self.path.as_path()));
+
+        if let Some(profile) = &self.profile {
+            if !PROFILES.contains(&profile.as_str()) {
+                return Err(ConfigError::invalid(
+                    "profile",
+                    format!(
+                        "unknown profile `{}`, expected one of {}",
+                        profile,
+                        PROFILES.join(", ")
+                    ),
+                ));
+            }
+            builder = builder.add_source(config::File::from(self.profile_path(profile)));
+        }
+
+        let mut environment = config::Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("__")
+            .separator("__")
+            .list_separator(",")
+            .try_parsing(true)
+            .source(self.env.clone());
+        for key in LIST_KEYS {
+            environment = environment.with_list_parse_key(key);
+        }
+        builder = builder.add_source(environment);
+
+        for (key, value) in &self.overrides {
+            builder = if LIST_KEYS.contains(&key.as_str()) {
+                let values: Vec<&str> = value.split(',').map(str::trim).collect();
+                builder.set_override(key.as_str(), values)?
+            } else {
+                builder.set_override(key.as_str(), value.as_str())?
+            };
+        }
+
+        let settings: Settings = builder.build()?.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// The configuration files this loader reads, as found on disk.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![resolve(&self.path)];
+        if let Some(profile) = &self.profile {
+            files.push(resolve(&self.profile_path(profile)));
+        }
+        files
+    }
+
+    /// `config.yaml` with profile `prod` becomes `config.prod.yaml`; a path
+    /// without an extension gets one picked by the `config` crate.
+    fn profile_path(&self, profile: &str) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "config".to_string());
+
+        let name = match self.path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, profile, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, profile),
+        };
+
+        self.path.with_file_name(name)
+    }
+}
+
+/// Adds the extension the `config` crate would pick to a path given
+/// without one.
+fn resolve(path: &Path) -> PathBuf {
+    if path.extension().is_some() {
+        return path.to_path_buf();
+    }
+
+    ["yaml", "yml", "toml", "json", "ini", "ron", "json5"]
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn get_config() -> Result<Settings, ConfigError> {
+    ConfigLoader::new().load()
+}
+
+/// A configuration that could not be read, or that was read but holds
+/// invalid values.
+#[derive(Debug)]
+pub enum ConfigError {
+    Source(config::ConfigError),
+    Invalid(Vec<InvalidSetting>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidSetting {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn invalid(key: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError::Invalid(vec![InvalidSetting {
+            key: key.into(),
+            message: message.into(),
+        }])
+    }
+
+    /// The keys of the settings that were rejected by validation.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            ConfigError::Source(_) => Vec::new(),
+            ConfigError::Invalid(settings) => settings.iter().map(|s| s.key.as_str()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Source(err) => write!(f, "{}", err),
+            ConfigError::Invalid(settings) => {
+                let messages: Vec<String> = settings
+                    .iter()
+                    .map(|setting| format!("`{}`: {}", setting.key, setting.message))
+                    .collect();
+                write!(f, "invalid configuration: {}", messages.join("; "))
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<config::ConfigError> for ConfigError {
+    fn from(err: config::ConfigError) -> Self {
+        ConfigError::Source(err)
+    }
 }
 
-pub fn get_config() -> Result<Settings, config::ConfigError> {
-    let settings = config::Config::builder()
-        .add_source(config::File::with_name("config"))
-        .build()?;
-    settings.try_deserialize()
+impl Settings {
+    /// Checks values that deserialize fine but cannot work, reporting every
+    /// offending key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut invalid = Vec::new();
+        let mut check = |failed: bool, key: String, message: &str| {
+            if failed {
+                invalid.push(InvalidSetting {
+                    key,
+                    message: message.to_string(),
+                });
+            }
+        };
+
+        check(
+            self.application.address.trim().is_empty(),
+            "application.address".into(),
+            "must not be empty",
+        );
+        check(
+            self.database.name.trim().is_empty(),
+            "database.name".into(),
+            "must not be empty",
+        );
+        for (i, field) in self.database.unique_fields.iter().enumerate() {
+            check(
+                field.trim().is_empty(),
+                format!("database.unique_fields[{}]", i),
+                "must not be empty",
+            );
+        }
+        check(
+            self.database.audit_file.trim().is_empty(),
+            "database.audit_file".into(),
+            "must not be empty",
+        );
+        check(
+            self.database.revisions_file.trim().is_empty(),
+            "database.revisions_file".into(),
+            "must not be empty",
+        );
+        check(
+            self.database.backup_dir.trim().is_empty(),
+            "database.backup_dir".into(),
+            "must not be empty",
+        );
+        check(
+            self.database.compact_after == 0,
+            "database.compact_after".into(),
+            "must be greater than 0",
+        );
+
+        let auth = &self.auth;
+        check(
+            auth.secret.is_empty(),
+            "auth.secret".into(),
+            "must not be empty",
+        );
+        check(
+            auth.access_token_ttl == 0,
+            "auth.access_token_ttl".into(),
+            "must be greater than 0",
+        );
+        check(
+            auth.refresh_token_ttl == 0,
+            "auth.refresh_token_ttl".into(),
+            "must be greater than 0",
+        );
+        check(
+            auth.signature_window == 0,
+            "auth.signature_window".into(),
+            "must be greater than 0",
+        );
+        for (i, key) in auth.signing_keys.iter().enumerate() {
+            check(
+                key.id.is_empty(),
+                format!("auth.signing_keys[{}].id", i),
+                "must not be empty",
+            );
+            check(
+                key.secret.is_empty(),
+                format!("auth.signing_keys[{}].secret", i),
+                "must not be empty",
+            );
+            check(
+                auth.signing_keys[..i]
+                    .iter()
+                    .any(|other| other.id == key.id),
+                format!("auth.signing_keys[{}].id", i),
+                "is used by another signing key",
+            );
+        }
+
+        check(
+            self.reload.watch && self.reload.interval_ms == 0,
+            "reload.interval_ms".into(),
+            "must be greater than 0",
+        );
+
+        check(
+            tracing_subscriber::EnvFilter::try_new(&self.logging.level).is_err(),
+            "logging.level".into(),
+            "is not a valid log filter",
+        );
+
+        let tls = &self.tls;
+        if tls.enabled {
+            check(tls.cert.is_empty(), "tls.cert".into(), "must not be empty");
+            check(tls.key.is_empty(), "tls.key".into(), "must not be empty");
+        }
+        check(
+            tls.require_client_cert && tls.client_ca.is_none(),
+            "tls.client_ca".into(),
+            "must be set when client certificates are required",
+        );
+
+        let mut types: Vec<_> = self.validation.iter().collect();
+        types.sort_by_key(|(name, _)| name.as_str());
+        for (name, fields) in types {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_by_key(|(field, _)| field.as_str());
+            for (field, rules) in fields {
+                let key = |rule: &str| format!("validation.{}.{}.{}", name, field, rule);
+
+                if let (Some(min), Some(max)) = (rules.min_length, rules.max_length) {
+                    check(min > max, key("min_length"), "must not exceed max_length");
+                }
+                if let Some(pattern) = &rules.pattern {
+                    check(
+                        Regex::new(pattern).is_err(),
+                        key("pattern"),
+                        "is not a valid regular expression",
+                    );
+                }
+            }
+        }
+
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(invalid))
+        }
+    }
 }
 
 impl ApplicationSettings {
@@ -28,8 +556,80 @@ impl ApplicationSettings {
     }
 }
 
+impl Default for ApplicationSettings {
+    fn default() -> Self {
+        Self {
+            port: 8000,
+            address: "127.0.0.1".to_string(),
+            shutdown_timeout: default_shutdown_timeout(),
+        }
+    }
+}
+
 impl DatabaseSettings {
     pub fn database_name(&self) -> String {
         self.name.clone()
     }
 }
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self {
+            name: "data.json".to_string(),
+            unique_fields: default_unique_fields(),
+            audit_file: default_audit_file(),
+            revisions_file: default_revisions_file(),
+            trash_retention: default_trash_retention(),
+            compact_after: default_compact_after(),
+            refresh_interval_ms: default_refresh_interval_ms(),
+            backups: default_backups(),
+            recover: default_recover(),
+            backup_dir: default_backup_dir(),
+        }
+    }
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert: "certs/server.pem".to_string(),
+            key: "certs/server.key".to_string(),
+            client_ca: None,
+            require_client_cert: false,
+            client_scopes: vec!["items:write".to_string()],
+        }
+    }
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+impl Default for ReloadSettings {
+    fn default() -> Self {
+        Self {
+            watch: true,
+            interval_ms: 2000,
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            secret: "secret-key".to_string(),
+            access_token_ttl: 15 * 60,
+            refresh_token_ttl: 14 * 24 * 60 * 60,
+            api_keys_file: "api_keys.json".to_string(),
+            revoked_tokens_file: "revoked_tokens.json".to_string(),
+            signing_keys: Vec::new(),
+            signature_window: 300,
+        }
+    }
+}
@@ -1,18 +1,306 @@
-use poem::{http::StatusCode, IntoResponse, Response};
+use crate::server::TYPED_PATH_PARAMS;
+use poem::{
+    error::{MethodNotAllowedError, NotFoundError, ParseJsonError, ParsePathError, ResponseError},
+    http::{header, StatusCode},
+    IntoResponse, PathPattern, Response,
+};
 use serde::Serialize;
-use serde_json::json;
+use std::fmt;
 
-#[derive(Debug, Serialize)]
-pub struct ApiError {
+const AUTH_REALM: &str = "playasia";
+
+/// The application's error type. Handlers and middleware return it and it
+/// is rendered as an RFC 7807 `application/problem+json` response.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest {
+        detail: String,
+        errors: Vec<FieldError>,
+    },
+    NotFound(String),
+    MethodNotAllowed(String),
+    Validation(Vec<FieldError>),
+    /// The request clashes with an existing resource, named by
+    /// `conflicting_id` when known.
+    Conflict {
+        detail: String,
+        conflicting_id: Option<u16>,
+    },
+    Storage(String),
+    Auth(JwtErrorKind),
+    Internal(String),
+    /// Any other error raised by poem itself, kept with its status.
+    Http(StatusCode, String),
+}
+
+/// A problem with one field of the request, such as a JSON body member or a
+/// path parameter.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
     pub message: String,
-    pub code: u16,
 }
 
-#[derive(Debug)]
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// RFC 7807 problem details body. `code` is a stable, machine-readable
+/// identifier that clients can match on; `type` is derived from it.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflicting_id: Option<u16>,
+    /// The `X-Request-Id` of the failed request, to quote in support tickets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest { .. } => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Conflict { .. } => "conflict",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Auth(kind) => kind.error_code(),
+            ApiError::Internal(_) => "internal_error",
+            ApiError::Http(status, _) => match *status {
+                StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+                status if status.is_client_error() => "bad_request",
+                _ => "internal_error",
+            },
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest { detail, .. }
+            | ApiError::NotFound(detail)
+            | ApiError::MethodNotAllowed(detail)
+            | ApiError::Http(_, detail)
+            | ApiError::Conflict { detail, .. }
+            | ApiError::Storage(detail)
+            | ApiError::Internal(detail) => detail,
+            ApiError::Validation(_) => "Request validation failed",
+            ApiError::Auth(kind) => kind.message(),
+        }
+    }
+
+    pub fn problem(&self, instance: Option<&str>) -> Problem {
+        let status = self.status();
+
+        Problem {
+            problem_type: format!("urn:playasia:problem:{}", self.code()),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: self.detail().to_string(),
+            instance: instance.map(str::to_string),
+            code: self.code(),
+            errors: match self {
+                ApiError::BadRequest { errors, .. } | ApiError::Validation(errors) => {
+                    errors.clone()
+                }
+                _ => Vec::new(),
+            },
+            conflicting_id: match self {
+                ApiError::Conflict { conflicting_id, .. } => *conflicting_id,
+                _ => None,
+            },
+            request_id: None,
+        }
+    }
+
+    /// Renders the error, naming the request path it occurred on and the ID
+    /// of the request.
+    pub fn to_response(&self, instance: Option<&str>, request_id: Option<&str>) -> Response {
+        let problem = Problem {
+            request_id: request_id.map(str::to_string),
+            ..self.problem(instance)
+        };
+        let body = serde_json::to_string(&problem).unwrap_or_else(|_| {
+            format!(
+                r#"{{"title": "Internal Server Error", "status": 500, "code": "{}"}}"#,
+                self.code()
+            )
+        });
+
+        let mut response = Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, "application/problem+json")
+            .body(body)
+            .into_response();
+
+        if let ApiError::Auth(kind) = self {
+            if let Ok(challenge) = kind.challenge().parse() {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, challenge);
+            }
+            // Lets outer middleware, such as metrics, see why it failed.
+            response.set_data(*kind);
+        }
+
+        response
+    }
+}
+
+impl ApiError {
+    /// Maps any error raised while handling a request to an `ApiError`,
+    /// giving poem's own extractor and routing errors field-level detail.
+    /// `path` is the request path, used to name offending path parameters.
+    pub fn from_poem(err: poem::Error, path: &str) -> Self {
+        if err.is::<ApiError>() {
+            return err
+                .downcast::<ApiError>()
+                .unwrap_or_else(|err| ApiError::Internal(err.to_string()));
+        }
+
+        if err.is::<NotFoundError>() {
+            return ApiError::NotFound(format!("No route matches {}", path));
+        }
+
+        if err.is::<MethodNotAllowedError>() {
+            return ApiError::MethodNotAllowed(format!("Method not allowed on {}", path));
+        }
+
+        if err.is::<ParsePathError>() {
+            let errors = match err.data::<PathPattern>() {
+                Some(pattern) => path_param_errors(&pattern.0, path),
+                None => Vec::new(),
+            };
+            return ApiError::BadRequest {
+                detail: "Invalid path parameter".to_string(),
+                errors,
+            };
+        }
+
+        if let Some(ParseJsonError::Parse(json_error)) = err.downcast_ref::<ParseJsonError>() {
+            return ApiError::BadRequest {
+                detail: "Invalid JSON body".to_string(),
+                errors: vec![json_field_error(json_error)],
+            };
+        }
+
+        ApiError::Http(err.status(), err.to_string())
+    }
+}
+
+/// Names each parameter of the matched route pattern whose value fails to
+/// parse, along with that value. poem does not say which parameter failed,
+/// so if none of them is known to be invalid, all of them are named.
+fn path_param_errors(pattern: &str, path: &str) -> Vec<FieldError> {
+    let params: Vec<(&str, &str)> = pattern
+        .split('/')
+        .zip(path.split('/'))
+        .filter_map(|(segment, value)| segment.strip_prefix(':').map(|name| (name, value)))
+        .collect();
+    let invalid: Vec<(&str, &str)> = params
+        .iter()
+        .copied()
+        .filter(|&(name, value)| !path_param_parses(pattern, name, value))
+        .collect();
+
+    let named = if invalid.is_empty() { params } else { invalid };
+    named
+        .into_iter()
+        .map(|(name, value)| FieldError::new(name, format!("invalid value `{}`", value)))
+        .collect()
+}
+
+/// Whether `value` parses as the type the route `pattern` takes path
+/// parameter `name` as. Parameters not in `TYPED_PATH_PARAMS` are strings.
+fn path_param_parses(pattern: &str, name: &str, value: &str) -> bool {
+    TYPED_PATH_PARAMS
+        .iter()
+        .filter(|(route, _)| *route == pattern)
+        .flat_map(|(_, params)| params.iter())
+        .find(|(param, _)| *param == name)
+        .is_none_or(|(_, parses)| parses(value))
+}
+
+fn json_field_error(err: &serde_json::Error) -> FieldError {
+    // serde only names the field in its message for these kinds of errors.
+    let message = err.to_string();
+    let field = ["missing field `", "unknown field `", "duplicate field `"]
+        .iter()
+        .find_map(|prefix| message.strip_prefix(prefix))
+        .and_then(|rest| rest.split('`').next())
+        .unwrap_or("body");
+
+    let message = match err.classify() {
+        serde_json::error::Category::Data => message
+            .rsplit_once(" at line ")
+            .map(|(message, _)| message.to_string())
+            .unwrap_or(message.clone()),
+        _ => message.clone(),
+    };
+
+    FieldError::new(field, message)
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.detail())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Http(status, _) => *status,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Storage(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Auth(kind) => kind.status(),
+        }
+    }
+
+    fn as_response(&self) -> Response {
+        self.to_response(None, None)
+    }
+}
+
+impl From<JwtErrorKind> for ApiError {
+    fn from(kind: JwtErrorKind) -> Self {
+        ApiError::Auth(kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JwtErrorKind {
     Expired,
     Invalid,
     Missing,
+    Malformed,
+    Revoked,
+    InsufficientScope,
+    InvalidSignature,
+    SignatureExpired,
+    Replayed,
 }
 
 impl JwtErrorKind {
@@ -21,58 +309,63 @@ impl JwtErrorKind {
             JwtErrorKind::Expired => "Token has expired",
             JwtErrorKind::Invalid => "Invalid token",
             JwtErrorKind::Missing => "Invalid authorization header",
+            JwtErrorKind::Malformed => "Malformed authorization header",
+            JwtErrorKind::Revoked => "Token has been revoked",
+            JwtErrorKind::InsufficientScope => "Insufficient scope",
+            JwtErrorKind::InvalidSignature => "Invalid request signature",
+            JwtErrorKind::SignatureExpired => "Request signature has expired",
+            JwtErrorKind::Replayed => "Request has already been processed",
         }
     }
 
-    pub fn code(&self) -> u16 {
+    pub fn status(&self) -> StatusCode {
         match self {
-            JwtErrorKind::Expired => StatusCode::BAD_REQUEST.as_u16(),
-            JwtErrorKind::Invalid => StatusCode::BAD_REQUEST.as_u16(),
-            JwtErrorKind::Missing => StatusCode::UNAUTHORIZED.as_u16(),
+            JwtErrorKind::Malformed => StatusCode::BAD_REQUEST,
+            JwtErrorKind::InsufficientScope => StatusCode::FORBIDDEN,
+            JwtErrorKind::Expired
+            | JwtErrorKind::Invalid
+            | JwtErrorKind::Missing
+            | JwtErrorKind::Revoked
+            | JwtErrorKind::InvalidSignature
+            | JwtErrorKind::SignatureExpired
+            | JwtErrorKind::Replayed => StatusCode::UNAUTHORIZED,
         }
     }
-}
-
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let body = serde_json::to_string(&self).unwrap_or_else(|_| {
-            format!(
-                r#"{{"message": "{}", "code": {}}}"#,
-                self.message, self.code
-            )
-        });
-
-        let status =
-            StatusCode::from_u16(self.code).unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR);
 
-        Response::builder()
-            .status(status)
-            .header("Content-type", "application/json")
-            .body(body)
-            .into_response()
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            JwtErrorKind::Expired => "token_expired",
+            JwtErrorKind::Invalid => "invalid_token",
+            JwtErrorKind::Missing => "missing_credentials",
+            JwtErrorKind::Malformed => "malformed_authorization",
+            JwtErrorKind::Revoked => "token_revoked",
+            JwtErrorKind::InsufficientScope => "insufficient_scope",
+            JwtErrorKind::InvalidSignature => "invalid_signature",
+            JwtErrorKind::SignatureExpired => "signature_expired",
+            JwtErrorKind::Replayed => "request_replayed",
+        }
     }
-}
 
-impl ApiError {
-    pub fn middleware_response(kind: JwtErrorKind) -> Response {
-        let message = kind.message();
-        let code = kind.code();
-
-        let body = serde_json::to_string(&json!({
-            "message": message,
-            "code": code,
-        }))
-        .unwrap_or_else(|_| {
-            format!(
-                r#"{{"message": "Internal server error", "code": {}}}"#,
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )
-        });
+    /// The RFC 6750 `WWW-Authenticate` challenge for this error. Requests
+    /// that carried no credentials at all get a bare challenge.
+    pub fn challenge(&self) -> String {
+        let error = match self {
+            JwtErrorKind::Missing => return format!(r#"Bearer realm="{}""#, AUTH_REALM),
+            JwtErrorKind::Malformed => "invalid_request",
+            JwtErrorKind::InsufficientScope => "insufficient_scope",
+            JwtErrorKind::Expired
+            | JwtErrorKind::Invalid
+            | JwtErrorKind::Revoked
+            | JwtErrorKind::InvalidSignature
+            | JwtErrorKind::SignatureExpired
+            | JwtErrorKind::Replayed => "invalid_token",
+        };
 
-        Response::builder()
-            .status(StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-            .header("Content-type", "application/json")
-            .body(body)
-            .into_response()
+        format!(
+            r#"Bearer realm="{}", error="{}", error_description="{}""#,
+            AUTH_REALM,
+            error,
+            self.message()
+        )
     }
 }
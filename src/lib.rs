@@ -1,5 +1,18 @@
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod cli;
 pub mod config;
 pub mod errors;
+pub mod logging;
+pub mod metrics;
 pub mod middleware;
+pub mod oplog;
+pub mod reload;
+pub mod revisions;
 pub mod routes;
+pub mod schema;
 pub mod server;
+pub mod store;
+pub mod tls;
+pub mod validation;
@@ -0,0 +1,37 @@
+use crate::config::{LogFormat, LoggingSettings};
+use std::error::Error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+/// Changes the level of the installed subscriber, e.g. on a config reload.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevel {
+    pub fn set(&self, level: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.handle.reload(EnvFilter::try_new(level)?)?;
+        Ok(())
+    }
+}
+
+/// Installs the global subscriber writing to stderr. `logging.level` is an
+/// `EnvFilter` directive such as `info` or `info,playasia=debug`.
+pub fn init(settings: &LoggingSettings) -> Result<LogLevel, Box<dyn Error + Send + Sync>> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&settings.level)?);
+
+    let output = match settings.format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Pretty => fmt::layer().with_writer(std::io::stderr).boxed(),
+    };
+
+    Registry::default().with(filter).with(output).try_init()?;
+    Ok(LogLevel { handle })
+}
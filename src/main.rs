@@ -1,8 +1,15 @@
-use playasia::config::get_config;
-use playasia::server::run;
+use clap::Parser;
+use playasia::cli::{execute, Cli};
+use std::process::ExitCode;
 
-fn main() -> std::io::Result<()> {
-    let config = get_config().expect("Failed to read config");
-    let address = config.application.connection_string();
-    run(address)
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match execute(cli, &mut std::io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
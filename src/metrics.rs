@@ -0,0 +1,163 @@
+use crate::errors::JwtErrorKind;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+const NAMESPACE: &str = "playasia";
+
+/// Label used for requests that did not match any route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// The application's Prometheus metrics, kept in a registry of their own
+/// and rendered by the `/metrics` endpoint.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    auth_failures: IntCounterVec,
+    storage_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+    items: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled.").namespace(NAMESPACE),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests.",
+            )
+            .namespace(NAMESPACE),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let auth_failures = IntCounterVec::new(
+            Opts::new(
+                "auth_failures_total",
+                "Requests rejected by authentication or authorization.",
+            )
+            .namespace(NAMESPACE),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let storage_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "storage_operation_duration_seconds",
+                "Time taken to read or write the data file.",
+            )
+            .namespace(NAMESPACE)
+            .buckets(vec![
+                0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+            ]),
+            &["operation"],
+        )
+        .expect("valid metric");
+        let storage_errors = IntCounterVec::new(
+            Opts::new(
+                "storage_errors_total",
+                "Failed reads and writes of the data file.",
+            )
+            .namespace(NAMESPACE),
+            &["operation"],
+        )
+        .expect("valid metric");
+        let items = IntGauge::with_opts(
+            Opts::new(
+                "items",
+                "Items in the data file as of the last storage access.",
+            )
+            .namespace(NAMESPACE),
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(http_requests.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(http_duration.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(auth_failures.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(storage_duration.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(storage_errors.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(items.clone()))
+            .expect("unique metric");
+
+        #[cfg(target_os = "linux")]
+        registry
+            .register(Box::new(
+                prometheus::process_collector::ProcessCollector::for_self(),
+            ))
+            .expect("unique metric");
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            auth_failures,
+            storage_duration,
+            storage_errors,
+            items,
+        }
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_auth_failure(&self, kind: JwtErrorKind) {
+        self.auth_failures
+            .with_label_values(&[kind.error_code()])
+            .inc();
+    }
+
+    /// Records a read or write of the data file.
+    pub fn observe_storage(&self, operation: &str, elapsed: Duration, ok: bool) {
+        self.storage_duration
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+        if !ok {
+            self.storage_errors.with_label_values(&[operation]).inc();
+        }
+    }
+
+    pub fn set_item_count(&self, count: usize) {
+        self.items.set(count as i64);
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Encoding into memory only fails for malformed metric families,
+        // which the registry does not let in.
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
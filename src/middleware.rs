@@ -1,33 +1,68 @@
+use crate::auth::{
+    decode_access_token, ApiKeyStore, AuthMethod, Claims, Identity, SignedRequest, TokenStore,
+    KEY_ID_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
 use crate::errors::{ApiError, JwtErrorKind};
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use crate::metrics::{Metrics, UNMATCHED_ROUTE};
+use crate::reload::LiveSettings;
+use crate::tls::PeerIdentities;
 use poem::{
-    error::Error as PoemError,
     http::{header, Method},
-    Endpoint, Middleware, Request,
+    Endpoint, IntoResponse, Middleware, PathPattern, Request, Response,
 };
-use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tracing::{field, Instrument, Span};
+use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
-}
+/// Paths that accept non-GET requests without credentials.
+const PUBLIC_PATHS: &[&str] = &["/auth/refresh"];
+
+const API_KEY_HEADER: &str = "X-API-Key";
 
 pub struct JwtMiddleware {
     pub secret: String,
+    tokens: TokenStore,
+    api_keys: ApiKeyStore,
+    live: Option<LiveSettings>,
 }
 
 impl JwtMiddleware {
     pub fn new(secret: impl Into<String>) -> Self {
         Self {
             secret: secret.into(),
+            tokens: TokenStore::new(),
+            api_keys: ApiKeyStore::in_memory(),
+            live: None,
         }
     }
+
+    /// Shares the given token store so that revocations made by the auth
+    /// handlers are honoured by the middleware.
+    pub fn with_token_store(mut self, tokens: TokenStore) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    /// Accepts API keys from the given store alongside Bearer tokens.
+    pub fn with_api_keys(mut self, api_keys: ApiKeyStore) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    /// Verifies tokens with the secret of the live settings, so that it can
+    /// be rotated by a reload, instead of the one given to `new`.
+    pub fn with_settings(mut self, live: LiveSettings) -> Self {
+        self.live = Some(live);
+        self
+    }
 }
 
 pub struct JwtMiddlewareImpl<E> {
     ep: E,
     secret: String,
+    tokens: TokenStore,
+    api_keys: ApiKeyStore,
+    live: Option<LiveSettings>,
 }
 
 impl<E: Endpoint> Middleware<E> for JwtMiddleware {
@@ -37,46 +72,507 @@ impl<E: Endpoint> Middleware<E> for JwtMiddleware {
         JwtMiddlewareImpl {
             ep,
             secret: self.secret.clone(),
+            tokens: self.tokens.clone(),
+            api_keys: self.api_keys.clone(),
+            live: self.live.clone(),
+        }
+    }
+}
+
+impl<E> JwtMiddlewareImpl<E> {
+    fn authenticate(&self, req: &Request) -> Result<(Identity, Option<Claims>), JwtErrorKind> {
+        if let Some(key) = req.headers().get(API_KEY_HEADER) {
+            let key = key.to_str().map_err(|_| JwtErrorKind::Malformed)?.trim();
+            if key.is_empty() {
+                return Err(JwtErrorKind::Malformed);
+            }
+            return self.authenticate_api_key(key);
+        }
+
+        let Some(auth_header) = req.headers().get(header::AUTHORIZATION) else {
+            return Err(JwtErrorKind::Missing);
+        };
+        let auth_header = auth_header.to_str().map_err(|_| JwtErrorKind::Malformed)?;
+
+        let token = match parse_authorization(auth_header)? {
+            (scheme, key) if scheme.eq_ignore_ascii_case("ApiKey") => {
+                return self.authenticate_api_key(key)
+            }
+            (scheme, token) if scheme.eq_ignore_ascii_case("Bearer") => token,
+            _ => return Err(JwtErrorKind::Missing),
+        };
+
+        let claims = match &self.live {
+            Some(live) => decode_access_token(token, &live.current().settings.auth.secret)?,
+            None => decode_access_token(token, &self.secret)?,
+        };
+
+        if let Some(jti) = &claims.jti {
+            if self.tokens.is_revoked(jti) {
+                return Err(JwtErrorKind::Revoked);
+            }
         }
+
+        Ok((Identity::from_jwt(&claims.sub, &claims.roles), Some(claims)))
+    }
+
+    fn authenticate_api_key(&self, key: &str) -> Result<(Identity, Option<Claims>), JwtErrorKind> {
+        let api_key = self.api_keys.verify(key).ok_or(JwtErrorKind::Invalid)?;
+
+        let identity = Identity {
+            sub: format!("api-key:{}", api_key.id),
+            scopes: api_key.scopes,
+            method: AuthMethod::ApiKey,
+        };
+
+        Ok((identity, None))
+    }
+}
+
+/// Adds the authenticated subject to the request's tracing span.
+fn record_sub(identity: &Identity) {
+    Span::current().record("sub", identity.sub.as_str());
+}
+
+/// Splits an `Authorization` header into its scheme and credentials. The
+/// scheme is returned as sent; callers compare it case-insensitively.
+fn parse_authorization(header: &str) -> Result<(&str, &str), JwtErrorKind> {
+    let header = header.trim();
+    let (scheme, credentials) = header
+        .split_once([' ', '\t'])
+        .map(|(scheme, credentials)| (scheme, credentials.trim()))
+        .unwrap_or((header, ""));
+
+    if scheme.is_empty()
+        || credentials.is_empty()
+        || credentials.contains(|c: char| c.is_ascii_whitespace())
+    {
+        return Err(JwtErrorKind::Malformed);
     }
+
+    Ok((scheme, credentials))
 }
 
 impl<E: Endpoint> Endpoint for JwtMiddlewareImpl<E> {
     type Output = E::Output;
 
-    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
-        if req.method() == Method::GET {
+    async fn call(&self, mut req: Request) -> poem::Result<Self::Output> {
+        // Already authenticated by an outer middleware, e.g. a signed request.
+        if req.extensions().get::<Identity>().is_some() {
+            return self.ep.call(req).await;
+        }
+
+        let authenticated = self.authenticate(&req);
+
+        // Reads stay public, but credentials sent with them are still picked
+        // up so that scoped GET endpoints can identify the caller.
+        if req.method() == Method::GET || PUBLIC_PATHS.contains(&req.uri().path()) {
+            if let Ok((identity, claims)) = authenticated {
+                record_sub(&identity);
+                req.extensions_mut().insert(identity);
+                if let Some(claims) = claims {
+                    req.extensions_mut().insert(claims);
+                }
+            }
+            return self.ep.call(req).await;
+        }
+
+        let (identity, claims) = authenticated.map_err(ApiError::Auth)?;
+
+        record_sub(&identity);
+        req.extensions_mut().insert(identity);
+        if let Some(claims) = claims {
+            req.extensions_mut().insert(claims);
+        }
+        self.ep.call(req).await
+    }
+}
+
+/// Authenticates requests signed with a shared key (see `auth::signing`).
+/// Requests without signature headers are passed on untouched, so this is
+/// meant to wrap `JwtMiddleware`, which then skips its own checks.
+pub struct SignatureMiddleware {
+    live: LiveSettings,
+}
+
+impl SignatureMiddleware {
+    pub fn new(live: LiveSettings) -> Self {
+        Self { live }
+    }
+}
+
+pub struct SignatureMiddlewareImpl<E> {
+    ep: E,
+    live: LiveSettings,
+}
+
+impl<E: Endpoint> Middleware<E> for SignatureMiddleware {
+    type Output = SignatureMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        SignatureMiddlewareImpl {
+            ep,
+            live: self.live.clone(),
+        }
+    }
+}
+
+impl<E: Endpoint> Endpoint for SignatureMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> poem::Result<Self::Output> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        let Some(signature) = header(SIGNATURE_HEADER) else {
             return self.ep.call(req).await;
+        };
+
+        let (Some(key_id), Some(timestamp), Some(nonce)) = (
+            header(KEY_ID_HEADER),
+            header(TIMESTAMP_HEADER).and_then(|value| value.parse::<u64>().ok()),
+            header(NONCE_HEADER),
+        ) else {
+            return Err(ApiError::Auth(JwtErrorKind::InvalidSignature).into());
+        };
+
+        let body = req.take_body().into_bytes().await?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+
+        let signed = SignedRequest {
+            method: req.method().as_str(),
+            path,
+            timestamp,
+            nonce: &nonce,
+            body: &body,
+        };
+
+        let live = self.live.current();
+        let key = live
+            .signatures
+            .verify(&key_id, &signed, &signature)
+            .map_err(ApiError::Auth)?;
+
+        let identity = Identity {
+            sub: format!("signing-key:{}", key.id),
+            scopes: key.scopes.clone(),
+            method: AuthMethod::Signature,
+        };
+
+        req.set_body(body);
+        record_sub(&identity);
+        req.extensions_mut().insert(identity);
+        self.ep.call(req).await
+    }
+}
+
+/// Authenticates requests arriving over a TLS connection whose client
+/// presented a verified certificate, using the certificate subject as `sub`.
+/// The scopes granted are read from the live `tls.client_scopes`.
+pub struct ClientCertMiddleware {
+    peers: PeerIdentities,
+    live: LiveSettings,
+}
+
+impl ClientCertMiddleware {
+    pub fn new(peers: PeerIdentities, live: LiveSettings) -> Self {
+        Self { peers, live }
+    }
+}
+
+pub struct ClientCertMiddlewareImpl<E> {
+    ep: E,
+    peers: PeerIdentities,
+    live: LiveSettings,
+}
+
+impl<E: Endpoint> Middleware<E> for ClientCertMiddleware {
+    type Output = ClientCertMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ClientCertMiddlewareImpl {
+            ep,
+            peers: self.peers.clone(),
+            live: self.live.clone(),
+        }
+    }
+}
+
+impl<E: Endpoint> Endpoint for ClientCertMiddlewareImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> poem::Result<Self::Output> {
+        let subject = req
+            .remote_addr()
+            .as_socket_addr()
+            .and_then(|addr| self.peers.get(addr));
+
+        if let Some(sub) = subject {
+            let identity = Identity {
+                sub,
+                scopes: self.live.current().settings.tls.client_scopes.clone(),
+                method: AuthMethod::ClientCertificate,
+            };
+            record_sub(&identity);
+            req.extensions_mut().insert(identity);
+        }
+
+        self.ep.call(req).await
+    }
+}
+
+/// Rejects requests whose authenticated identity lacks `scope`. Must be
+/// applied inside `JwtMiddleware`, which resolves the identity.
+pub struct RequireScope {
+    scope: &'static str,
+}
+
+impl RequireScope {
+    pub fn new(scope: &'static str) -> Self {
+        Self { scope }
+    }
+}
+
+pub struct RequireScopeImpl<E> {
+    ep: E,
+    scope: &'static str,
+}
+
+impl<E: Endpoint> Middleware<E> for RequireScope {
+    type Output = RequireScopeImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RequireScopeImpl {
+            ep,
+            scope: self.scope,
+        }
+    }
+}
+
+impl<E: Endpoint> Endpoint for RequireScopeImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let kind = match req.extensions().get::<Identity>() {
+            Some(identity) if identity.has_scope(self.scope) => return self.ep.call(req).await,
+            Some(_) => JwtErrorKind::InsufficientScope,
+            None => JwtErrorKind::Missing,
+        };
+
+        Err(ApiError::Auth(kind).into())
+    }
+}
+
+/// Renders every error raised below it, including poem's own extractor
+/// and routing errors, as problem details that name the request path as
+/// their `instance` and carry the request ID set by `TracingMiddleware`.
+pub struct ErrorMiddleware;
+
+pub struct ErrorMiddlewareImpl<E> {
+    ep: E,
+}
+
+impl<E: Endpoint> Middleware<E> for ErrorMiddleware {
+    type Output = ErrorMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ErrorMiddlewareImpl { ep }
+    }
+}
+
+impl<E: Endpoint> Endpoint for ErrorMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let path = req.uri().path().to_string();
+        let request_id = req.extensions().get::<RequestId>().cloned();
+
+        match self.ep.call(req).await {
+            Ok(output) => Ok(output.into_response()),
+            Err(err) => {
+                let pattern = err.data::<PathPattern>().cloned();
+                let request_id = request_id.as_ref().map(|id| id.0.as_str());
+                let mut response =
+                    ApiError::from_poem(err, &path).to_response(Some(&path), request_id);
+                if let Some(pattern) = pattern {
+                    response.set_data(pattern);
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// Records every request in `Metrics`, labelled with the route template it
+/// matched rather than its path, so that IDs do not multiply the series.
+/// Must wrap `ErrorMiddleware` to see the final status of failed requests.
+pub struct MetricsMiddleware {
+    metrics: Metrics,
+}
+
+impl MetricsMiddleware {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+pub struct MetricsMiddlewareImpl<E> {
+    ep: E,
+    metrics: Metrics,
+}
+
+impl<E: Endpoint> Middleware<E> for MetricsMiddleware {
+    type Output = MetricsMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        MetricsMiddlewareImpl {
+            ep,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<E: Endpoint> Endpoint for MetricsMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let started = Instant::now();
+        let method = req.method().clone();
+
+        let (response, pattern) = match self.ep.call(req).await {
+            Ok(output) => {
+                let response = output.into_response();
+                let pattern = response.data::<PathPattern>().cloned();
+                (Ok(response), pattern)
+            }
+            Err(err) => {
+                let pattern = err.data::<PathPattern>().cloned();
+                (Err(err), pattern)
+            }
+        };
+
+        let status = match &response {
+            Ok(response) => response.status(),
+            Err(err) => err.status(),
+        };
+        let route = pattern
+            .as_ref()
+            .map(|pattern| &*pattern.0)
+            .unwrap_or(UNMATCHED_ROUTE);
+
+        self.metrics
+            .observe_request(method.as_str(), route, status.as_u16(), started.elapsed());
+        if let Some(kind) = response
+            .as_ref()
+            .ok()
+            .and_then(|response| response.data::<JwtErrorKind>())
+        {
+            self.metrics.observe_auth_failure(*kind);
         }
 
-        let auth_header = req
-            .headers()
-            .get(header::AUTHORIZATION)
-            .and_then(|header| header.to_str().ok());
+        response
+    }
+}
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The ID of the request being handled, as sent by the client in
+/// `X-Request-Id` or generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
 
-        let token = match auth_header {
-            Some(header) if header.starts_with("Bearer") => header[7..].to_string(),
-            _ => {
-                return Err(PoemError::from_response(ApiError::middleware_response(
-                    JwtErrorKind::Missing,
-                )))
+impl RequestId {
+    /// Accepts client IDs of up to 128 visible ASCII characters; anything
+    /// else is replaced so that it cannot corrupt logs.
+    fn from_header(value: Option<&str>) -> Self {
+        match value {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= 128
+                    && id.bytes().all(|byte| byte.is_ascii_graphic()) =>
+            {
+                Self(id.to_string())
             }
+            _ => Self(Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+/// Runs each request in a tracing span carrying its ID, method, route,
+/// status, latency and authenticated `sub`, logs its completion, and echoes
+/// the ID in the `X-Request-Id` response header. Must wrap every other
+/// middleware so that they log within the span.
+pub struct TracingMiddleware;
+
+pub struct TracingMiddlewareImpl<E> {
+    ep: E,
+}
+
+impl<E: Endpoint> Middleware<E> for TracingMiddleware {
+    type Output = TracingMiddlewareImpl<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        TracingMiddlewareImpl { ep }
+    }
+}
+
+impl<E: Endpoint> Endpoint for TracingMiddlewareImpl<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> poem::Result<Self::Output> {
+        let request_id = RequestId::from_header(
+            req.headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok()),
+        );
+        req.extensions_mut().insert(request_id.clone());
+
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id.0,
+            method = %req.method(),
+            path = %req.uri().path(),
+            route = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+            sub = field::Empty,
+        );
+
+        let started = Instant::now();
+        let mut response = match self.ep.call(req).instrument(span.clone()).await {
+            Ok(output) => output.into_response(),
+            Err(err) => err.into_response(),
         };
+        let status = response.status();
+
+        span.record(
+            "route",
+            response
+                .data::<PathPattern>()
+                .map(|pattern| &*pattern.0)
+                .unwrap_or(UNMATCHED_ROUTE),
+        );
+        span.record("status", status.as_u16());
+        span.record("latency_ms", started.elapsed().as_secs_f64() * 1000.0);
+        span.in_scope(|| {
+            if status.is_server_error() {
+                tracing::error!("request failed");
+            } else {
+                tracing::info!("request completed");
+            }
+        });
 
-        match decode::<Claims>(
-            &token,
-            &DecodingKey::from_secret(self.secret.as_bytes()),
-            &Validation::default(),
-        ) {
-            Ok(_token_data) => self.ep.call(req).await,
-            Err(err) => match err.kind() {
-                jsonwebtoken::errors::ErrorKind::ExpiredSignature => Err(PoemError::from_response(
-                    ApiError::middleware_response(JwtErrorKind::Expired),
-                )),
-                _ => Err(PoemError::from_response(ApiError::middleware_response(
-                    JwtErrorKind::Invalid,
-                ))),
-            },
+        if let Ok(value) = request_id.0.parse() {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
         }
+        Ok(response)
     }
 }
@@ -0,0 +1,217 @@
+use crate::routes::Item;
+use crate::schema::{self, VERSION};
+use crate::store::StoreError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A change to the item collection, as recorded in the operation log.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// Adds the item, or replaces the item with its ID.
+    Put {
+        item: Item,
+    },
+    /// Adds or replaces several items at once, so that a batch is never
+    /// half-applied.
+    PutAll {
+        items: Vec<Item>,
+    },
+    Remove {
+        id: u16,
+    },
+}
+
+impl Op {
+    /// The number of items the operation touches.
+    pub fn item_count(&self) -> usize {
+        match self {
+            Op::PutAll { items } => items.len(),
+            Op::Put { .. } | Op::Remove { .. } => 1,
+        }
+    }
+}
+
+/// First line of the operation log, naming the snapshot the operations
+/// apply to by its contents alone, so that touching or copying the data file
+/// does not separate it from its log. A log whose header does not match the
+/// data file was written for another snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogHeader {
+    /// Hex SHA-256 of the snapshot's contents.
+    pub snapshot: String,
+}
+
+impl LogHeader {
+    pub fn for_snapshot(data: &[u8]) -> Self {
+        Self {
+            snapshot: hex::encode(Sha256::digest(data)),
+        }
+    }
+}
+
+/// The operations read back from the log.
+pub struct Replay {
+    pub ops: Vec<Op>,
+    /// Whether the log belongs to the snapshot. New operations can only be
+    /// appended to a matching log; otherwise a new log is started. The
+    /// operations of a log that does not match are read all the same, so
+    /// that the caller can tell whether they would be lost.
+    pub matches: bool,
+    /// The length of the log without a last line cut short, if it has one.
+    torn: Option<u64>,
+}
+
+/// Operations stored one JSON object per line after a `LogHeader`. Each
+/// append is a single write followed by an fsync, so its cost does not
+/// depend on the size of the catalog.
+pub struct OpLog {
+    path: PathBuf,
+}
+
+impl OpLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the operations recorded on top of the snapshot identified by
+    /// `header`, written in data file version `version`. A last line without
+    /// its newline was cut short by a crash and is truncated away, if the log
+    /// matches; any other unreadable line is corruption.
+    pub fn replay(&self, header: &LogHeader, version: u32) -> Result<Replay, StoreError> {
+        let replay = self.inspect(header, version)?;
+
+        if let Some(complete) = replay.torn.filter(|_| replay.matches) {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)?
+                .set_len(complete)?;
+        }
+        Ok(replay)
+    }
+
+    /// Reads the operations like `replay`, but leaves a cut short last line
+    /// in place.
+    pub fn inspect(&self, header: &LogHeader, version: u32) -> Result<Replay, StoreError> {
+        let unmatched = Replay {
+            ops: Vec::new(),
+            matches: false,
+            torn: None,
+        };
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(unmatched),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut lines = data.split_inclusive(|&byte| byte == b'\n');
+        let first = lines.next().unwrap_or_default();
+        let Ok(recorded) = serde_json::from_slice::<LogHeader>(first) else {
+            return Ok(unmatched);
+        };
+
+        let mut ops = Vec::new();
+        let mut complete = data.len();
+        let mut offset = first.len();
+        for (number, line) in lines.enumerate() {
+            if !line.ends_with(b"\n") {
+                complete = offset;
+                break;
+            }
+            if !line.trim_ascii().is_empty() {
+                ops.push(decode(line, version).map_err(|err| {
+                    StoreError::Corrupt(format!(
+                        "{} line {}: {}",
+                        self.path.display(),
+                        number + 2,
+                        err
+                    ))
+                })?);
+            }
+            offset += line.len();
+        }
+
+        Ok(Replay {
+            ops,
+            matches: &recorded == header,
+            torn: (complete < data.len()).then_some(complete as u64),
+        })
+    }
+
+    /// The header the log was started with, or `None` if there is no log or
+    /// its first line cannot be read.
+    pub fn header(&self) -> Option<LogHeader> {
+        let mut first = String::new();
+        BufReader::new(File::open(&self.path).ok()?)
+            .read_line(&mut first)
+            .ok()?;
+        serde_json::from_str(&first).ok()
+    }
+
+    pub fn append(&self, ops: &[Op]) -> Result<(), StoreError> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&encode(ops)?)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the log with one for the snapshot identified by `header`,
+    /// holding `ops`.
+    pub fn start(&self, header: &LogHeader, ops: &[Op]) -> Result<(), StoreError> {
+        let mut data = serde_json::to_vec(header)?;
+        data.push(b'\n');
+        data.extend(encode(ops)?);
+
+        write_atomically(&self.path, &data)
+    }
+}
+
+/// Reads an operation written in data file version `version`, upgrading its
+/// items to the current version.
+fn decode(line: &[u8], version: u32) -> Result<Op, StoreError> {
+    if version == VERSION {
+        return Ok(serde_json::from_slice(line)?);
+    }
+
+    let mut op: Value = serde_json::from_slice(line)?;
+    let items = match op.get_mut("item") {
+        Some(item) => std::slice::from_mut(item),
+        None => match op.get_mut("items") {
+            Some(Value::Array(items)) => items.as_mut_slice(),
+            _ => &mut [],
+        },
+    };
+    schema::upgrade(items, version)?;
+    Ok(serde_json::from_value(op)?)
+}
+
+fn encode(ops: &[Op]) -> Result<Vec<u8>, StoreError> {
+    let mut data = Vec::new();
+    for op in ops {
+        serde_json::to_writer(&mut data, op)?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+/// Writes to a temporary file that then replaces `path`, so that `path` is
+/// never left half-written.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), StoreError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use crate::auth::SignatureVerifier;
+use crate::config::{ConfigError, ConfigLoader, Settings};
+use crate::logging::LogLevel;
+use crate::validation::ValidationRules;
+use arc_swap::ArcSwap;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Settings that only take effect when the server is restarted. Changes to
+/// them are still reported, and applied to the live settings, on reload.
+const RESTART_REQUIRED: &[&str] = &[
+    "application.",
+    "database.",
+    "tls.enabled",
+    "tls.cert",
+    "tls.key",
+    "tls.client_ca",
+    "tls.require_client_cert",
+    "reload.",
+    "auth.api_keys_file",
+    "auth.revoked_tokens_file",
+    "logging.format",
+];
+
+/// Everything derived from one version of the settings, swapped as a unit
+/// so that a request never sees a mix of old and new values.
+pub struct Snapshot {
+    pub settings: Settings,
+    pub validation: ValidationRules,
+    pub signatures: SignatureVerifier,
+}
+
+/// The settings used by middleware and handlers, replaceable at runtime.
+#[derive(Clone)]
+pub struct LiveSettings {
+    current: Arc<ArcSwap<Snapshot>>,
+    reload_error: Arc<Mutex<Option<String>>>,
+}
+
+impl LiveSettings {
+    pub fn new(settings: Settings) -> Result<Self, ConfigError> {
+        let signatures =
+            SignatureVerifier::new(&settings.auth.signing_keys, settings.auth.signature_window);
+        let snapshot = snapshot(settings, signatures)?;
+
+        Ok(Self {
+            current: Arc::new(ArcSwap::from_pointee(snapshot)),
+            reload_error: Arc::default(),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.current.load_full()
+    }
+
+    /// Why the most recent reload was rejected, if it was.
+    pub fn reload_error(&self) -> Option<String> {
+        self.reload_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    fn record_reload<T>(&self, result: &Result<T, ConfigError>) {
+        *self
+            .reload_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) =
+            result.as_ref().err().map(ToString::to_string);
+    }
+
+    /// Validates `settings` and swaps them in, returning what changed. The
+    /// current settings are kept if the new ones are invalid.
+    pub fn replace(&self, settings: Settings) -> Result<Vec<Change>, ConfigError> {
+        settings.validate()?;
+
+        let previous = self.current();
+        let signatures = previous
+            .signatures
+            .with_keys(&settings.auth.signing_keys, settings.auth.signature_window);
+        let changes = diff(&previous.settings, &settings);
+
+        self.current
+            .store(Arc::new(snapshot(settings, signatures)?));
+        Ok(changes)
+    }
+}
+
+fn snapshot(settings: Settings, signatures: SignatureVerifier) -> Result<Snapshot, ConfigError> {
+    let validation = ValidationRules::new(settings.validation.clone())
+        .map_err(|err| ConfigError::invalid("validation", err.to_string()))?;
+
+    Ok(Snapshot {
+        settings,
+        validation,
+        signatures,
+    })
+}
+
+/// One setting that differs between two configurations. Values are `None`
+/// where the setting is absent, e.g. an added signing key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Change {
+    pub fn requires_restart(&self) -> bool {
+        RESTART_REQUIRED
+            .iter()
+            .any(|prefix| self.key.starts_with(prefix))
+    }
+
+    fn is_secret(&self) -> bool {
+        self.key.ends_with("secret")
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| match value {
+            None => "(unset)".to_string(),
+            Some(_) if self.is_secret() => "(redacted)".to_string(),
+            Some(value) => value.clone(),
+        };
+
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.key,
+            show(&self.old),
+            show(&self.new)
+        )?;
+        if self.requires_restart() {
+            write!(f, " (takes effect after a restart)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists every leaf setting that differs, ordered by key.
+pub fn diff(old: &Settings, new: &Settings) -> Vec<Change> {
+    let old = flatten(old);
+    let mut new = flatten(new);
+
+    let mut changes = Vec::new();
+    for (key, old_value) in old {
+        let new_value = new.remove(&key);
+        if new_value.as_ref() != Some(&old_value) {
+            changes.push(Change {
+                key,
+                old: Some(old_value),
+                new: new_value,
+            });
+        }
+    }
+    changes.extend(new.into_iter().map(|(key, value)| Change {
+        key,
+        old: None,
+        new: Some(value),
+    }));
+
+    changes.sort_by(|a, b| a.key.cmp(&b.key));
+    changes
+}
+
+fn flatten(settings: &Settings) -> BTreeMap<String, String> {
+    fn walk(prefix: String, value: Value, out: &mut BTreeMap<String, String>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    let key = if prefix.is_empty() {
+                        key
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(key, value, out);
+                }
+            }
+            // Lists of plain values read best as a whole.
+            Value::Array(values) if values.iter().any(|v| v.is_object()) => {
+                for (i, value) in values.into_iter().enumerate() {
+                    walk(format!("{}[{}]", prefix, i), value, out);
+                }
+            }
+            Value::Null => {}
+            Value::String(value) => {
+                out.insert(prefix, value);
+            }
+            value => {
+                out.insert(prefix, value.to_string());
+            }
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    if let Ok(value) = serde_json::to_value(settings) {
+        walk(String::new(), value, &mut out);
+    }
+    out
+}
+
+/// Reloads the live settings from the configuration files.
+pub struct Reloader {
+    loader: ConfigLoader,
+    live: LiveSettings,
+    log_level: Option<LogLevel>,
+}
+
+impl Reloader {
+    pub fn new(loader: ConfigLoader, live: LiveSettings) -> Self {
+        Self {
+            loader,
+            live,
+            log_level: None,
+        }
+    }
+
+    /// Also applies `logging.level` to the installed subscriber on reload.
+    pub fn with_log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    pub fn reload(&self) -> Result<Vec<Change>, ConfigError> {
+        let result = self
+            .loader
+            .load()
+            .and_then(|settings| self.live.replace(settings));
+        self.live.record_reload(&result);
+
+        if let (Ok(_), Some(log_level)) = (&result, &self.log_level) {
+            let level = &self.live.current().settings.logging.level;
+            if let Err(err) = log_level.set(level) {
+                tracing::warn!(level = %level, "failed to apply log level: {}", err);
+            }
+        }
+        result
+    }
+
+    /// Reloads on `SIGHUP` and, if enabled, whenever a configuration file
+    /// changes, until the process exits. Outcomes are logged.
+    pub async fn watch(self) {
+        let reload = self.live.current().settings.reload.clone();
+        let mut interval = tokio::time::interval(Duration::from_millis(reload.interval_ms.max(1)));
+        let mut seen = self.modified();
+
+        #[cfg(unix)]
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+        loop {
+            #[cfg(unix)]
+            let hangup_received = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_received = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = hangup_received => {
+                    tracing::info!("SIGHUP received, reloading configuration");
+                }
+                _ = interval.tick(), if reload.watch => {
+                    let modified = self.modified();
+                    if modified == seen {
+                        continue;
+                    }
+                    seen = modified;
+                    tracing::info!("configuration files changed, reloading");
+                }
+            }
+
+            self.log(self.reload());
+        }
+    }
+
+    fn log(&self, result: Result<Vec<Change>, ConfigError>) {
+        match result {
+            Ok(changes) if changes.is_empty() => {
+                tracing::info!("configuration reloaded, nothing changed")
+            }
+            Ok(changes) => {
+                tracing::info!(changed = changes.len(), "configuration reloaded");
+                for change in changes {
+                    tracing::info!(key = %change.key, "{}", change);
+                }
+            }
+            Err(err) => tracing::error!(
+                "configuration reload rejected, keeping current settings: {}",
+                err
+            ),
+        }
+    }
+
+    fn modified(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        self.loader
+            .files()
+            .into_iter()
+            .map(|file| {
+                let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+                (file, modified)
+            })
+            .collect()
+    }
+}
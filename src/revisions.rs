@@ -0,0 +1,122 @@
+use crate::routes::Item;
+use crate::store::StoreError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// One version of an item. `item` is absent for the revision that deleted
+/// it. Items that predate the revision history get a first revision with a
+/// `timestamp` of 0, holding their value when history started.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Revision {
+    pub item_id: u16,
+    /// Numbered from 1 for each item.
+    pub rev: u32,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub item: Option<Item>,
+}
+
+/// Revisions stored one JSON object per line, in the order they were made.
+/// The file is only appended to, by the item store while it holds its write
+/// lock.
+pub struct RevisionLog {
+    path: PathBuf,
+}
+
+impl RevisionLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn append(&self, revisions: &[Revision]) -> Result<(), StoreError> {
+        if revisions.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = String::new();
+        for revision in revisions {
+            lines.push_str(&serde_json::to_string(revision)?);
+            lines.push('\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&self.path)?;
+
+        // Cut off a line left without its newline by an append that was cut
+        // short, so that it does not run into these.
+        let mut end = file.seek(SeekFrom::End(0))?;
+        if end > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::Start(end - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                let mut data = Vec::new();
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut data)?;
+                end = complete(&data) as u64;
+                file.set_len(end)?;
+                tracing::warn!(
+                    revisions_file = %self.path.display(),
+                    "cut off a revision that was only partly written"
+                );
+            }
+            file.seek(SeekFrom::Start(end))?;
+        }
+
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Every revision, oldest first. A last line that was only partly
+    /// written is left out.
+    pub fn all(&self) -> Result<Vec<Revision>, StoreError> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        data[..complete(data.as_bytes())]
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    /// The revisions of one item, oldest first.
+    pub fn history(&self, item_id: u16) -> Result<Vec<Revision>, StoreError> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|revision| revision.item_id == item_id)
+            .collect())
+    }
+
+    /// For every item with a history, its latest revision made at or before
+    /// `timestamp`, or `None` if it was only created afterwards.
+    pub fn as_of(&self, timestamp: u64) -> Result<BTreeMap<u16, Option<Revision>>, StoreError> {
+        let mut latest = BTreeMap::new();
+        for revision in self.all()? {
+            let entry = latest.entry(revision.item_id).or_insert(None);
+            if revision.timestamp <= timestamp {
+                *entry = Some(revision);
+            }
+        }
+        Ok(latest)
+    }
+}
+
+/// The length of `data` up to and including its last newline.
+fn complete(data: &[u8]) -> usize {
+    data.iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1)
+}
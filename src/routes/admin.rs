@@ -0,0 +1,162 @@
+use crate::audit::{AuditAction, AuditEntry, AuditFilter, AuditLog};
+use crate::auth::{now, ApiKey, ApiKeyStore, Identity};
+use crate::backup::{BackupInfo, Backups};
+use crate::errors::ApiError;
+use crate::middleware::RequestId;
+use crate::routes::item::record;
+use crate::store::{ItemStore, StoreError};
+use poem::web::{Data, Json, Path, Query};
+use poem::{handler, http::StatusCode, IntoResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Lifetime of the key in seconds. Keys without one never expire.
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKeyResponse {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[handler]
+pub fn create_api_key(
+    Json(payload): Json<CreateApiKeyRequest>,
+    api_keys: Data<&ApiKeyStore>,
+) -> Result<impl IntoResponse, ApiError> {
+    let expires_at = payload.expires_in.map(|ttl| now() + ttl);
+
+    let (api_key, key) = api_keys
+        .create(&payload.name, payload.scopes, expires_at)
+        .map_err(|err| ApiError::Storage(format!("Failed to create API key: {}", err)))?;
+
+    Ok(Json(CreatedApiKeyResponse { api_key, key }).with_status(StatusCode::CREATED))
+}
+
+#[handler]
+pub fn list_api_keys(api_keys: Data<&ApiKeyStore>) -> impl IntoResponse {
+    Json(api_keys.list())
+}
+
+#[handler]
+pub fn revoke_api_key(
+    Path(id): Path<String>,
+    api_keys: Data<&ApiKeyStore>,
+) -> Result<impl IntoResponse, ApiError> {
+    let revoked = api_keys
+        .revoke(&id)
+        .map_err(|err| ApiError::Storage(format!("Failed to revoke API key: {}", err)))?;
+
+    match revoked {
+        Some(api_key) => Ok(Json(api_key).with_status(StatusCode::OK)),
+        None => Err(ApiError::NotFound(format!(
+            "API key with id {} not found",
+            id
+        ))),
+    }
+}
+
+/// Lists audit entries, oldest first, optionally narrowed down by
+/// `item_id`, `sub` and a `from`/`to` timestamp range.
+#[handler]
+pub async fn list_audit_entries(
+    Query(filter): Query<AuditFilter>,
+    audit: Data<&Arc<AuditLog>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(
+        audit.blocking(move |audit| audit.query(&filter)).await?,
+    ))
+}
+
+/// Takes a backup of the catalog. Writes wait while the items are copied,
+/// so a backup never holds part of a change.
+#[handler]
+pub async fn create_backup(
+    backups: Data<&Arc<Backups>>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let backup = backups
+        .blocking(&store, |backups, store| backups.create(store))
+        .await
+        .map_err(|err| ApiError::Storage(format!("Failed to create backup: {}", err)))?;
+
+    Ok(Json(backup).with_status(StatusCode::CREATED))
+}
+
+/// Lists the backups, oldest first.
+#[handler]
+pub async fn list_backups(
+    backups: Data<&Arc<Backups>>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(
+        backups
+            .blocking(&store, |backups, _| backups.list())
+            .await?,
+    ))
+}
+
+#[derive(Serialize)]
+pub struct RestoredBackupResponse {
+    #[serde(flatten)]
+    pub backup: BackupInfo,
+    /// The number of items added, changed or removed by the restore.
+    pub items_changed: usize,
+}
+
+/// Replaces the catalog with a backup. Every item it changes is recorded as
+/// a restore in the audit log.
+#[handler]
+pub async fn restore_backup(
+    Path(name): Path<String>,
+    backups: Data<&Arc<Backups>>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let restored = {
+        let name = name.clone();
+        backups
+            .blocking(&store, move |backups, store| backups.restore(&name, store))
+            .await
+    };
+    let restored = match restored {
+        Ok(Some(restored)) => restored,
+        Ok(None) => return Err(ApiError::NotFound(format!("Backup {} not found", name))),
+        Err(err @ (StoreError::Corrupt(_) | StoreError::UnsupportedVersion(_))) => {
+            return Err(ApiError::Conflict {
+                detail: format!("Backup {} cannot be restored: {}", name, err),
+                conflicting_id: None,
+            })
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    // Every change is recorded even if one fails, so that no more are left
+    // out of the log than need be.
+    let items_changed = restored.changes.len();
+    let mut recorded = Ok(());
+    for (before, after) in restored.changes {
+        let result = record(
+            &audit,
+            AuditEntry::new(&identity, &request_id, AuditAction::Restore, before, after),
+        )
+        .await;
+        recorded = recorded.and(result);
+    }
+    recorded?;
+
+    Ok(Json(RestoredBackupResponse {
+        backup: restored.backup,
+        items_changed,
+    })
+    .with_status(StatusCode::OK))
+}
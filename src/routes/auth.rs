@@ -0,0 +1,73 @@
+use crate::auth::{decode_refresh_token, issue_token_pair, now, Claims, TokenStore};
+use crate::errors::{ApiError, JwtErrorKind};
+use crate::reload::LiveSettings;
+use poem::web::{Data, Json};
+use poem::{handler, http::StatusCode, IntoResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LoggedOutResponse {
+    pub message: String,
+}
+
+#[handler]
+pub fn refresh(
+    Json(payload): Json<RefreshRequest>,
+    live: Data<&LiveSettings>,
+    tokens: Data<&TokenStore>,
+) -> Result<impl IntoResponse, ApiError> {
+    let live = live.current();
+    let settings = &live.settings.auth;
+    let claims = decode_refresh_token(&payload.refresh_token, &settings.secret)?;
+
+    tokens.consume_refresh(&claims, now() + settings.refresh_token_ttl)?;
+
+    let pair = issue_token_pair(settings, &claims.sub, &claims.roles, Some(claims.fam))
+        .map_err(|err| ApiError::Internal(format!("Failed to issue tokens: {}", err)))?;
+
+    Ok(Json(pair).with_status(StatusCode::OK))
+}
+
+#[handler]
+pub fn logout(
+    payload: Option<Json<LogoutRequest>>,
+    claims: Data<&Claims>,
+    live: Data<&LiveSettings>,
+    tokens: Data<&TokenStore>,
+) -> Result<impl IntoResponse, ApiError> {
+    let live = live.current();
+    let settings = &live.settings.auth;
+
+    if let Some(jti) = &claims.jti {
+        tokens
+            .revoke(jti, claims.exp as u64)
+            .map_err(|err| ApiError::Storage(format!("Failed to revoke token: {}", err)))?;
+    }
+
+    if let Some(refresh_token) = payload.and_then(|Json(body)| body.refresh_token) {
+        let refresh_claims = decode_refresh_token(&refresh_token, &settings.secret)?;
+
+        if refresh_claims.sub != claims.sub {
+            return Err(JwtErrorKind::Invalid.into());
+        }
+
+        tokens
+            .revoke_family(&refresh_claims.fam, now() + settings.refresh_token_ttl)
+            .map_err(|err| ApiError::Storage(format!("Failed to revoke token: {}", err)))?;
+    }
+
+    Ok(Json(LoggedOutResponse {
+        message: "Logged out successfully".to_string(),
+    })
+    .with_status(StatusCode::OK))
+}
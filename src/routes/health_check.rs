@@ -1,6 +1,157 @@
+use crate::audit::AuditLog;
+use crate::auth::ApiKeyStore;
+use crate::reload::LiveSettings;
+use crate::store::ItemStore;
+use poem::web::{Data, Json};
 use poem::{handler, http::StatusCode, IntoResponse, Response};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[handler]
 pub fn health_check() -> impl IntoResponse {
     Response::builder().status(StatusCode::OK).finish()
 }
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// Working, but something needs attention. Does not affect readiness.
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: CheckStatus,
+    pub checks: Vec<CheckResult>,
+}
+
+fn run_check(
+    name: &'static str,
+    check: impl FnOnce() -> (CheckStatus, Option<String>),
+) -> CheckResult {
+    let started = Instant::now();
+    let (status, detail) = check();
+
+    CheckResult {
+        name,
+        status,
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        detail,
+    }
+}
+
+/// Liveness: the process is up and serving requests.
+#[handler]
+pub fn liveness() -> impl IntoResponse {
+    Json(HealthReport {
+        status: CheckStatus::Pass,
+        checks: Vec::new(),
+    })
+}
+
+/// Readiness: the dependencies needed to serve requests are in order.
+/// Responds with 503 if any check fails.
+#[handler]
+pub async fn readiness(
+    live: Data<&LiveSettings>,
+    items: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    api_keys: Data<&ApiKeyStore>,
+) -> impl IntoResponse {
+    let storage = items
+        .blocking(|items| {
+            run_check("storage", || match (items.check(), items.recovery()) {
+                (Ok(count), None) => (CheckStatus::Pass, Some(format!("{} items", count))),
+                (Ok(count), Some(recovery)) => (
+                    CheckStatus::Warn,
+                    Some(format!("{} items; {}", count, recovery)),
+                ),
+                (Err(err), _) => (CheckStatus::Fail, Some(err.to_string())),
+            })
+        })
+        .await;
+
+    let current = live.current();
+    let settings = &current.settings;
+
+    let checks = vec![
+        storage,
+        run_check("audit", || match audit.failure() {
+            Some(err) => (
+                CheckStatus::Fail,
+                Some(format!("last entry could not be recorded: {}", err)),
+            ),
+            None => (CheckStatus::Pass, None),
+        }),
+        run_check("config", || {
+            match (settings.validate(), live.reload_error()) {
+                (Err(err), _) => (CheckStatus::Fail, Some(err.to_string())),
+                (Ok(()), Some(err)) => (
+                    CheckStatus::Warn,
+                    Some(format!("last reload rejected: {}", err)),
+                ),
+                (Ok(()), None) => (CheckStatus::Pass, None),
+            }
+        }),
+        run_check("keys", || {
+            if settings.auth.secret.is_empty() {
+                return (
+                    CheckStatus::Fail,
+                    Some("token secret is not set".to_string()),
+                );
+            }
+            if settings.tls.enabled {
+                for file in [&settings.tls.cert, &settings.tls.key] {
+                    if let Err(err) = std::fs::File::open(file) {
+                        return (CheckStatus::Fail, Some(format!("{}: {}", file, err)));
+                    }
+                }
+            }
+
+            match api_keys.check() {
+                Ok(active) => (
+                    CheckStatus::Pass,
+                    Some(format!(
+                        "{} active API keys, {} signing keys",
+                        active,
+                        settings.auth.signing_keys.len()
+                    )),
+                ),
+                Err(err) => (
+                    CheckStatus::Fail,
+                    Some(format!("{}: {}", settings.auth.api_keys_file, err)),
+                ),
+            }
+        }),
+    ];
+
+    let failed = checks.iter().any(|check| check.status == CheckStatus::Fail);
+    let report = HealthReport {
+        status: if failed {
+            CheckStatus::Fail
+        } else {
+            CheckStatus::Pass
+        },
+        checks,
+    };
+
+    Json(report)
+        .with_status(if failed {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        })
+        .with_header("Cache-Control", "no-store")
+}
@@ -1,30 +1,35 @@
+use crate::audit::{AuditAction, AuditEntry, AuditLog};
+use crate::auth::Identity;
 use crate::errors::ApiError;
-use poem::web::{Json, Path};
-use poem::{handler, http::StatusCode, Error as PoemError, IntoResponse};
+use crate::middleware::RequestId;
+use crate::store::{ItemStore, StoreError};
+use crate::validation::{FieldRules, Valid, Validate};
+use poem::web::{Data, Json, Path, Query};
+use poem::{handler, http::StatusCode, IntoResponse};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs};
+use std::sync::Arc;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Item {
     pub id: u16,
     pub name: String,
+    /// When the item was moved to the trash, as a Unix timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>,
 }
 
 impl Item {
-    fn get_all() -> Result<Vec<Self>, Box<dyn Error>> {
-        let data = fs::read_to_string("data.json")?;
-        if data.trim().is_empty() {
-            return Ok(Vec::new());
+    pub fn new(id: u16, name: String) -> Self {
+        Self {
+            id,
+            name,
+            deleted_at: None,
         }
-
-        let items = serde_json::from_str(&data)?;
-        Ok(items)
     }
 
-    fn write_to_file(file_path: Option<String>, data: Vec<Self>) -> Result<(), Box<dyn Error>> {
-        let file_path = file_path.unwrap_or("data.json".to_string());
-        let _ = fs::write(file_path, serde_json::to_string_pretty(&data)?);
-        Ok(())
+    /// Whether the item is not in the trash.
+    pub fn is_live(&self) -> bool {
+        self.deleted_at.is_none()
     }
 }
 
@@ -33,126 +38,102 @@ pub struct RequestBody {
     pub name: String,
 }
 
+impl Validate for RequestBody {
+    const NAME: &'static str = "item";
+
+    fn rules() -> Vec<(&'static str, FieldRules)> {
+        vec![(
+            "name",
+            FieldRules::new().required().trim().length(1, 200).pattern(
+                r#"^[\p{L}\p{M}\p{N}\p{Zs}\-_:;.,'"!?&()/+#]+$"#,
+                "may only contain letters, digits, spaces and common punctuation",
+            ),
+        )]
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemsQuery {
+    /// Unix timestamp at which to view the catalog, instead of now.
+    pub as_of: Option<u64>,
+}
+
 #[handler]
-pub fn get_all_items() -> Result<impl IntoResponse, PoemError> {
-    let items = Item::get_all().map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to retrieve items: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
+pub async fn get_all_items(
+    Query(query): Query<ItemsQuery>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let items = store
+        .blocking(move |store| match query.as_of {
+            Some(timestamp) => store.as_of(timestamp),
+            None => store.all(),
+        })
+        .await?;
 
     Ok(Json(items))
 }
 
 #[handler]
-pub fn get_item(Path(id): Path<u16>) -> Result<impl IntoResponse, PoemError> {
-    let items = Item::get_all().map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to retrieve items: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    match items.into_iter().find(|item| item.id == id) {
-        Some(item) => Ok(Json(item)),
-        None => Err(PoemError::from_response(
-            ApiError {
-                message: "Item not found".to_string(),
-                code: StatusCode::NOT_FOUND.as_u16(),
-            }
-            .into_response(),
-        )),
+pub async fn get_item(
+    Path(id): Path<u16>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    match store.blocking(move |store| store.get(id)).await {
+        Ok(item) => Ok(Json(item)),
+        Err(StoreError::NotFound(_)) => Err(ApiError::NotFound("Item not found".to_string())),
+        Err(err) => Err(err.into()),
     }
 }
 
 #[handler]
-pub async fn create(Json(payload): Json<RequestBody>) -> Result<impl IntoResponse, PoemError> {
-    let mut items = Item::get_all().map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to retrieve items: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    let next_id = items.iter().map(|item| item.id).max().unwrap_or(0) + 1;
-
-    let new_item = Item {
-        id: next_id,
-        name: payload.name,
-    };
-
-    items.push(new_item.clone());
-
-    let _updated_data = serde_json::to_string_pretty(&items).map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to create an item: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    let _ = Item::write_to_file(None, items).map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to write to file: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
+pub async fn create(
+    Valid(payload): Valid<RequestBody>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let new_item = store
+        .blocking(move |store| store.create(payload.name))
+        .await?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Create,
+            None,
+            Some(new_item.clone()),
+        ),
+    )
+    .await?;
 
     Ok(Json(new_item).with_status(StatusCode::CREATED))
 }
 
 #[handler]
-pub fn edit(
+pub async fn edit(
     Path(id): Path<u16>,
-    Json(payload): Json<RequestBody>,
-) -> Result<impl IntoResponse, PoemError> {
-    let mut items = Item::get_all().map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to retrieve items: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    let item = items.iter_mut().find(|item| item.id == id).ok_or_else(|| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Item with id {} not found", id),
-                code: StatusCode::NOT_FOUND.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    item.name = payload.name;
-    let updated_item = item.clone();
-
-    let _ = Item::write_to_file(None, items).map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to write to file: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
+    Valid(payload): Valid<RequestBody>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (previous, updated_item) = store
+        .blocking(move |store| store.update(id, payload.name))
+        .await?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Update,
+            Some(previous),
+            Some(updated_item.clone()),
+        ),
+    )
+    .await?;
 
     Ok(Json(updated_item).with_status(StatusCode::OK))
 }
@@ -163,41 +144,167 @@ pub struct DeletedMessageResponse {
 }
 
 #[handler]
-pub fn delete(Path(id): Path<u16>) -> Result<impl IntoResponse, PoemError> {
-    let mut items = Item::get_all().map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to retrieve items: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    let item_position = items.iter().position(|item| item.id == id).ok_or_else(|| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Item with id {} not found", id),
-                code: StatusCode::NOT_FOUND.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
-
-    let _ = items.remove(item_position);
-
-    let _ = Item::write_to_file(None, items).map_err(|err| {
-        PoemError::from_response(
-            ApiError {
-                message: format!("Failed to write to file: {}", err),
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-            }
-            .into_response(),
-        )
-    })?;
+pub async fn delete(
+    Path(id): Path<u16>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let removed = store.blocking(move |store| store.delete(id)).await?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Delete,
+            Some(removed),
+            None,
+        ),
+    )
+    .await?;
 
     Ok(Json(DeletedMessageResponse {
         message: "Item deleted successfully".to_string(),
     })
     .with_status(StatusCode::OK))
 }
+
+#[handler]
+pub async fn list_trash(store: Data<&Arc<ItemStore>>) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(store.blocking(|store| store.trash()).await?))
+}
+
+#[handler]
+pub async fn restore_item(
+    Path(id): Path<u16>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (trashed, restored_item) = store
+        .blocking(move |store| store.undelete(id))
+        .await
+        .map_err(not_in_trash)?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Restore,
+            Some(trashed),
+            Some(restored_item.clone()),
+        ),
+    )
+    .await?;
+
+    Ok(Json(restored_item).with_status(StatusCode::OK))
+}
+
+#[handler]
+pub async fn purge_item(
+    Path(id): Path<u16>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let purged = store
+        .blocking(move |store| store.purge(id))
+        .await
+        .map_err(not_in_trash)?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Purge,
+            Some(purged),
+            None,
+        ),
+    )
+    .await?;
+
+    Ok(Json(DeletedMessageResponse {
+        message: "Item purged successfully".to_string(),
+    })
+    .with_status(StatusCode::OK))
+}
+
+fn not_in_trash(err: StoreError) -> ApiError {
+    match err {
+        StoreError::NotFound(id) => {
+            ApiError::NotFound(format!("Item with id {} not found in trash", id))
+        }
+        err => err.into(),
+    }
+}
+
+#[handler]
+pub async fn list_revisions(
+    Path(id): Path<u16>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(
+        store.blocking(move |store| store.revisions(id)).await?,
+    ))
+}
+
+#[handler]
+pub async fn get_revision(
+    Path((id, rev)): Path<(u16, u32)>,
+    store: Data<&Arc<ItemStore>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(
+        store.blocking(move |store| store.revision(id, rev)).await?,
+    ))
+}
+
+#[handler]
+pub async fn restore_revision(
+    Path((id, rev)): Path<(u16, u32)>,
+    store: Data<&Arc<ItemStore>>,
+    audit: Data<&Arc<AuditLog>>,
+    identity: Data<&Identity>,
+    request_id: Data<&RequestId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (previous, restored_item) = store.blocking(move |store| store.restore(id, rev)).await?;
+    record(
+        &audit,
+        AuditEntry::new(
+            &identity,
+            &request_id,
+            AuditAction::Restore,
+            previous,
+            Some(restored_item.clone()),
+        ),
+    )
+    .await?;
+
+    Ok(Json(restored_item).with_status(StatusCode::OK))
+}
+
+/// Appends to the audit log. The change has already been stored by then and
+/// is kept, but a failure to audit it fails the request with a storage
+/// error; the log counts it and fails readiness until the next entry is
+/// recorded.
+pub(crate) async fn record(audit: &Arc<AuditLog>, entry: AuditEntry) -> Result<(), ApiError> {
+    let (item_id, action) = (entry.item_id, entry.action);
+
+    audit
+        .blocking(move |audit| audit.record(&entry))
+        .await
+        .map_err(|err| {
+            tracing::error!(
+                item_id,
+                action = ?action,
+                "failed to record audit entry: {}",
+                err
+            );
+            ApiError::Storage(format!(
+                "Storage error: the change was stored but not audited: {}",
+                err
+            ))
+        })
+}
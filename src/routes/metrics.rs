@@ -0,0 +1,12 @@
+use crate::metrics::Metrics;
+use poem::web::Data;
+use poem::{handler, http::StatusCode, IntoResponse};
+use prometheus::TEXT_FORMAT;
+
+#[handler]
+pub fn prometheus_metrics(metrics: Data<&Metrics>) -> impl IntoResponse {
+    metrics
+        .render()
+        .with_status(StatusCode::OK)
+        .with_content_type(TEXT_FORMAT)
+}
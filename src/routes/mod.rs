@@ -1,5 +1,11 @@
+pub mod admin;
+pub mod auth;
 pub mod health_check;
 pub mod item;
+pub mod metrics;
 
+pub use admin::*;
+pub use auth::*;
 pub use health_check::*;
 pub use item::*;
+pub use metrics::*;
@@ -0,0 +1,280 @@
+use crate::routes::Item;
+use crate::store::StoreError;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+/// A step from one version of the data file to the next. Migrations only
+/// ever move forward, one version at a time, and work on the file as JSON
+/// so that they can read shapes the current `Item` no longer accepts.
+pub struct Migration {
+    /// The version the migration upgrades to.
+    pub version: u32,
+    pub description: &'static str,
+    /// Checks a file written at this version before it is upgraded.
+    pub check: fn(&Map<String, Value>) -> Result<(), StoreError>,
+    /// Rewrites a file of the previous version into this version's shape.
+    pub upgrade: fn(Value) -> Result<Value, StoreError>,
+    /// Rewrites one item in place, in the data file and in the operation
+    /// log, for migrations that change the shape of items.
+    pub upgrade_item: Option<fn(&mut Map<String, Value>)>,
+}
+
+/// Every migration, in order. Version 1 is the original bare item array; a
+/// file at version `n` is checked by `MIGRATIONS[n - 2]` and brought up to
+/// date by the migrations after it.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "wrap the item array in a versioned envelope",
+        check: |_| Ok(()),
+        upgrade: |file| {
+            let Value::Array(items) = file else {
+                return Err(StoreError::Corrupt("expected an item array".to_string()));
+            };
+            Ok(json!({"version": 2, "items": items}))
+        },
+        upgrade_item: None,
+    },
+    Migration {
+        version: 3,
+        description: "record a checksum of the items",
+        check: |envelope| verify(envelope, items(envelope)?),
+        upgrade: |file| {
+            let mut envelope = into_envelope(file)?;
+            let checksum = checksum(items(&envelope)?)?;
+            envelope.insert("version".to_string(), json!(3));
+            envelope.insert("checksum".to_string(), Value::String(checksum));
+            Ok(Value::Object(envelope))
+        },
+        upgrade_item: None,
+    },
+    Migration {
+        version: 4,
+        description: "record the highest item ID assigned",
+        check: |envelope| {
+            let mut content = envelope.clone();
+            content.remove("checksum");
+            verify(envelope, &Value::Object(content))
+        },
+        upgrade: |file| {
+            let mut envelope = into_envelope(file)?;
+            let highest = items(&envelope)?
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item.get("id").and_then(Value::as_u64))
+                .max()
+                .unwrap_or(0);
+            envelope.remove("checksum");
+            envelope.insert("version".to_string(), json!(4));
+            envelope.insert("last_id".to_string(), json!(highest));
+            let checksum = checksum(&Value::Object(envelope.clone()))?;
+            envelope.insert("checksum".to_string(), Value::String(checksum));
+            Ok(Value::Object(envelope))
+        },
+        upgrade_item: None,
+    },
+];
+
+/// The version written by this build.
+pub const VERSION: u32 = 1 + MIGRATIONS.len() as u32;
+
+/// A migration applied while decoding, and how many items it changed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub version: u32,
+    pub description: &'static str,
+    /// How many items it changed, or `None` for a migration that leaves
+    /// items alone.
+    pub items_changed: Option<usize>,
+}
+
+/// The contents of a data file, upgraded to the current version.
+#[derive(Debug)]
+pub struct Decoded {
+    /// The version the file was written in.
+    pub version: u32,
+    pub items: Vec<Item>,
+    /// The highest ID ever assigned, which is never handed out again. Files
+    /// from before it was recorded use the highest ID they hold.
+    pub last_id: u16,
+    /// The migrations it took to bring the file up to date.
+    pub steps: Vec<Step>,
+}
+
+/// Reads a data file: either a versioned envelope,
+/// `{"version": n, "checksum": "...", "last_id": n, "items": [...]}`, or a
+/// bare array from before versions were recorded. An empty file holds no
+/// items. A file that does not parse, or does not pass the check of its
+/// version, is corrupt.
+pub fn decode(data: &[u8]) -> Result<Decoded, StoreError> {
+    if data.trim_ascii().is_empty() {
+        return Ok(Decoded {
+            version: VERSION,
+            items: Vec::new(),
+            last_id: 0,
+            steps: Vec::new(),
+        });
+    }
+
+    let mut file: Value =
+        serde_json::from_slice(data).map_err(|err| StoreError::Corrupt(err.to_string()))?;
+    let version = match &file {
+        Value::Array(_) => 1,
+        Value::Object(envelope) => {
+            let version = envelope
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| StoreError::Corrupt("missing version".to_string()))?;
+            let version = u32::try_from(version).unwrap_or(u32::MAX);
+            if version > VERSION {
+                return Err(StoreError::UnsupportedVersion(version));
+            }
+            let migration = MIGRATIONS
+                .iter()
+                .find(|migration| migration.version == version)
+                .ok_or_else(|| StoreError::Corrupt(format!("unknown version {}", version)))?;
+            (migration.check)(envelope)?;
+            version
+        }
+        _ => {
+            return Err(StoreError::Corrupt(
+                "expected an item array or a versioned envelope".to_string(),
+            ))
+        }
+    };
+
+    let mut steps = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let items_changed = match migration.upgrade_item {
+            Some(upgrade_item) => {
+                let items = match &mut file {
+                    Value::Array(items) => items,
+                    Value::Object(envelope) => match envelope.get_mut("items") {
+                        Some(Value::Array(items)) => items,
+                        _ => return Err(StoreError::Corrupt("missing item array".to_string())),
+                    },
+                    _ => unreachable!("the file was checked to be an array or an object"),
+                };
+                Some(upgrade_items(items, upgrade_item)?)
+            }
+            None => None,
+        };
+        file = (migration.upgrade)(file)?;
+
+        steps.push(Step {
+            version: migration.version,
+            description: migration.description,
+            items_changed,
+        });
+    }
+
+    let mut envelope = into_envelope(file)?;
+    let Some(Value::Array(items)) = envelope.remove("items") else {
+        return Err(StoreError::Corrupt("missing item array".to_string()));
+    };
+    let last_id = envelope
+        .get("last_id")
+        .and_then(Value::as_u64)
+        .and_then(|last_id| u16::try_from(last_id).ok())
+        .ok_or_else(|| StoreError::Corrupt("invalid last_id".to_string()))?;
+    let items: Vec<Item> = items
+        .into_iter()
+        .map(serde_json::from_value)
+        .collect::<Result<_, _>>()
+        .map_err(|err| StoreError::Corrupt(err.to_string()))?;
+
+    let highest = items.iter().map(|item| item.id).max().unwrap_or(0);
+    Ok(Decoded {
+        version,
+        last_id: last_id.max(highest),
+        items,
+        steps,
+    })
+}
+
+/// Writes `items` as a data file of the current version, recording
+/// `last_id` as the highest ID assigned.
+pub fn encode(items: &[&Item], last_id: u16) -> Result<Vec<u8>, StoreError> {
+    let Value::Object(mut envelope) = json!({
+        "version": VERSION,
+        "last_id": last_id,
+        "items": items,
+    }) else {
+        unreachable!("the envelope is an object");
+    };
+
+    let checksum = checksum(&Value::Object(envelope.clone()))?;
+    envelope.insert("checksum".to_string(), Value::String(checksum));
+    Ok(serde_json::to_vec_pretty(&envelope)?)
+}
+
+/// Hex SHA-256 of `content` in compact JSON. Content is hashed as parsed
+/// values, so the checksum does not depend on how the file is formatted.
+fn checksum(content: &Value) -> Result<String, StoreError> {
+    Ok(hex::encode(Sha256::digest(serde_json::to_vec(content)?)))
+}
+
+/// Checks the checksum recorded in `envelope` against `content`.
+fn verify(envelope: &Map<String, Value>, content: &Value) -> Result<(), StoreError> {
+    let recorded = envelope.get("checksum").and_then(Value::as_str);
+    if recorded != Some(checksum(content)?.as_str()) {
+        return Err(StoreError::Corrupt("checksum mismatch".to_string()));
+    }
+    Ok(())
+}
+
+fn into_envelope(file: Value) -> Result<Map<String, Value>, StoreError> {
+    match file {
+        Value::Object(envelope) => Ok(envelope),
+        _ => Err(StoreError::Corrupt(
+            "expected a versioned envelope".to_string(),
+        )),
+    }
+}
+
+/// The item array of `envelope`.
+fn items(envelope: &Map<String, Value>) -> Result<&Value, StoreError> {
+    match envelope.get("items") {
+        Some(items @ Value::Array(_)) => Ok(items),
+        _ => Err(StoreError::Corrupt("missing item array".to_string())),
+    }
+}
+
+/// Runs the item upgrades of the migrations after `version` on `items`, as
+/// found in an operation log written at that version.
+pub fn upgrade(items: &mut [Value], version: u32) -> Result<(), StoreError> {
+    if version > VERSION {
+        return Err(StoreError::UnsupportedVersion(version));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        if let Some(upgrade_item) = migration.upgrade_item {
+            upgrade_items(items, upgrade_item)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites each of `items` with `upgrade_item`, returning how many changed.
+fn upgrade_items(
+    items: &mut [Value],
+    upgrade_item: fn(&mut Map<String, Value>),
+) -> Result<usize, StoreError> {
+    let mut changed = 0;
+    for item in items.iter_mut() {
+        let Value::Object(fields) = item else {
+            return Err(StoreError::Corrupt(format!(
+                "item is not an object: {}",
+                item
+            )));
+        };
+        let before = fields.clone();
+        upgrade_item(fields);
+        if *fields != before {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
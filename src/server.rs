@@ -1,17 +1,351 @@
-use crate::middleware::JwtMiddleware;
-use crate::routes::{create, delete, edit, get_all_items, get_item, health_check};
-use poem::{get, listener::TcpListener, Endpoint, EndpointExt, Route, Server};
+use crate::audit::{AuditAction, AuditEntry, AuditLog};
+use crate::auth::{ApiKeyStore, TokenStore, SCOPE_ADMIN, SCOPE_ITEMS_WRITE};
+use crate::backup::Backups;
+use crate::config::{ConfigLoader, Settings};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::middleware::{
+    ClientCertMiddleware, ErrorMiddleware, JwtMiddleware, MetricsMiddleware, RequireScope,
+    SignatureMiddleware, TracingMiddleware,
+};
+use crate::reload::{LiveSettings, Reloader};
+use crate::routes::item::record;
+use crate::routes::Item;
+use crate::routes::{
+    create, create_api_key, create_backup, delete, edit, get_all_items, get_item, get_revision,
+    health_check, list_api_keys, list_audit_entries, list_backups, list_revisions, list_trash,
+    liveness, logout, prometheus_metrics, purge_item, readiness, refresh, restore_backup,
+    restore_item, restore_revision, revoke_api_key,
+};
+use crate::store::{ItemStore, StoreError};
+use crate::tls::{server_config, PeerIdentities, TlsListener};
+use poem::listener::{Acceptor, Listener, TcpListener};
+use poem::{get, post, Endpoint, EndpointExt, Route, Server};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
 
+/// Installs the logger and serves the app until `SIGINT` or `SIGTERM`. With
+/// a `loader`, the settings it produced are reloaded on `SIGHUP` and
+/// whenever its files change.
 #[tokio::main]
-pub async fn run(address: String) -> Result<(), std::io::Error> {
-    let app = create_app();
-    Server::new(TcpListener::bind(address)).run(app).await
+pub async fn run(settings: Settings, loader: Option<ConfigLoader>) -> Result<(), std::io::Error> {
+    let log_level = logging::init(&settings.logging).map_err(std::io::Error::other)?;
+    let address = settings.application.connection_string();
+    let tls = settings.tls.clone();
+    let trash_retention = settings.database.trash_retention;
+    let live = LiveSettings::new(settings)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let state = AppState::new(live.clone(), PeerIdentities::new())?;
+
+    if let Some(loader) = loader {
+        tokio::spawn(
+            Reloader::new(loader, live)
+                .with_log_level(log_level)
+                .watch(),
+        );
+    }
+    if trash_retention > 0 {
+        tokio::spawn(purge_trash(
+            state.items.clone(),
+            state.audit.clone(),
+            trash_retention,
+        ));
+    }
+
+    tracing::info!(address = %address, tls = tls.enabled, "listening");
+
+    if tls.enabled {
+        let config = server_config(&tls)?;
+        let listener = TlsListener::new(TcpListener::bind(address), config, state.peers.clone());
+        serve(listener.into_acceptor().await?, state, shutdown_signal()).await
+    } else {
+        let listener = TcpListener::bind(address);
+        serve(listener.into_acceptor().await?, state, shutdown_signal()).await
+    }
+}
+
+/// Serves the app until `signal` completes. The server then stops accepting
+/// connections and gives in-flight requests up to
+/// `application.shutdown_timeout` seconds to finish before closing them.
+/// Pending store writes are flushed before returning.
+pub async fn serve<A>(
+    acceptor: A,
+    state: AppState,
+    signal: impl Future<Output = ()>,
+) -> Result<(), std::io::Error>
+where
+    A: Acceptor + 'static,
+{
+    let timeout = Duration::from_secs(state.live.current().settings.application.shutdown_timeout);
+    let app = create_app_from(&state);
+
+    let result = Server::new_with_acceptor(acceptor)
+        .run_with_graceful_shutdown(app, signal, Some(timeout))
+        .await;
+
+    state
+        .items
+        .blocking(|items| items.flush())
+        .await
+        .map_err(|err| std::io::Error::other(format!("Failed to flush item store: {}", err)))?;
+    tracing::info!("item store flushed, exiting");
+
+    result
+}
+
+/// Purges items that have been in the trash for `retention` seconds, at
+/// startup and then every hour or every `retention`, whichever is shorter.
+async fn purge_trash(items: Arc<ItemStore>, audit: Arc<AuditLog>, retention: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(retention.min(60 * 60)));
+
+    loop {
+        interval.tick().await;
+        if let Err(err) = purge_expired(&items, &audit, retention).await {
+            tracing::error!("failed to purge the trash: {}", err);
+        }
+    }
+}
+
+/// Purges the items that have been in the trash for `retention` seconds,
+/// recording each purge in the audit log as made by the system, and
+/// returns them.
+pub async fn purge_expired(
+    items: &Arc<ItemStore>,
+    audit: &Arc<AuditLog>,
+    retention: u64,
+) -> Result<Vec<Item>, StoreError> {
+    let purged = items
+        .blocking(move |items| items.purge_expired(retention))
+        .await?;
+    if purged.is_empty() {
+        return Ok(purged);
+    }
+
+    let run_id = Uuid::new_v4().to_string();
+    for item in &purged {
+        // There is no request to fail: a failure is logged and fails
+        // readiness, and the other purges are still recorded.
+        let _ = record(
+            audit,
+            AuditEntry::system(&run_id, AuditAction::Purge, Some(item.clone()), None),
+        )
+        .await;
+    }
+    tracing::info!(
+        purged = purged.len(),
+        run_id,
+        "purged items past the trash retention period"
+    );
+    Ok(purged)
+}
+
+/// Completes on the first `SIGINT` (Ctrl-C) or `SIGTERM`.
+async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => tracing::info!("SIGINT received, draining in-flight requests"),
+        _ = terminate => tracing::info!("SIGTERM received, draining in-flight requests"),
+    }
+}
+
+/// State shared by the endpoints of one app. `run` keeps it to coordinate
+/// shutdown; settings read at startup only, such as the data file, keep
+/// their initial values for its lifetime.
+#[derive(Clone)]
+pub struct AppState {
+    pub live: LiveSettings,
+    pub peers: PeerIdentities,
+    pub tokens: TokenStore,
+    pub api_keys: ApiKeyStore,
+    pub items: Arc<ItemStore>,
+    pub audit: Arc<AuditLog>,
+    pub backups: Arc<Backups>,
+    pub metrics: Metrics,
+}
+
+impl AppState {
+    /// Fails if the API keys or revoked tokens cannot be loaded: starting
+    /// without them would reject every key or accept revoked tokens, and the
+    /// next change would overwrite them.
+    pub fn new(live: LiveSettings, peers: PeerIdentities) -> Result<Self, std::io::Error> {
+        let current = live.current();
+        let settings = &current.settings;
+        let api_keys = ApiKeyStore::load(&settings.auth.api_keys_file).map_err(|err| {
+            std::io::Error::other(format!(
+                "failed to load API keys from {}: {}",
+                settings.auth.api_keys_file, err
+            ))
+        })?;
+        let tokens = TokenStore::load(&settings.auth.revoked_tokens_file).map_err(|err| {
+            std::io::Error::other(format!(
+                "failed to load revoked tokens from {}: {}",
+                settings.auth.revoked_tokens_file, err
+            ))
+        })?;
+        let metrics = Metrics::new();
+        let items = Arc::new(ItemStore::new(&settings.database).with_metrics(metrics.clone()));
+        // Checks the files, which also seeds the item count. Files that are
+        // still broken are reported by readiness.
+        let checked = if settings.database.recover {
+            items.recover()
+        } else {
+            items.check().map(|_| None)
+        };
+        match checked {
+            Ok(Some(recovery)) => tracing::warn!("recovered the item store: {}", recovery),
+            Ok(None) => {}
+            Err(err) => tracing::error!("item store failed its integrity check: {}", err),
+        }
+        let audit =
+            Arc::new(AuditLog::new(&settings.database.audit_file).with_metrics(metrics.clone()));
+        let backups = Arc::new(Backups::new(&settings.database.backup_dir));
+
+        Ok(Self {
+            live,
+            peers,
+            tokens,
+            api_keys,
+            items,
+            audit,
+            backups,
+            metrics,
+        })
+    }
 }
 
 pub fn create_app() -> impl Endpoint {
+    create_app_with(&Settings::default())
+}
+
+pub fn create_app_with(settings: &Settings) -> impl Endpoint {
+    create_app_with_peers(settings, PeerIdentities::new())
+}
+
+/// Builds the app, authenticating TLS clients whose certificates were
+/// recorded in `peers` by a `TlsListener`.
+pub fn create_app_with_peers(settings: &Settings, peers: PeerIdentities) -> impl Endpoint {
+    let live = LiveSettings::new(settings.clone()).expect("Invalid settings");
+    create_app_with_live(live, peers)
+}
+
+/// Builds the app around settings that can be replaced while it runs.
+pub fn create_app_with_live(live: LiveSettings, peers: PeerIdentities) -> impl Endpoint {
+    create_app_from(&AppState::new(live, peers).expect("Failed to set up the app"))
+}
+
+/// A path parameter, with a check that a value parses as its type.
+pub type PathParam = (&'static str, fn(&str) -> bool);
+
+/// The path parameters each route takes as something other than a string.
+/// poem's `ParsePathError` does not say which parameter failed, so invalid
+/// ones are named from this table, which has to follow the `Path`
+/// extractors of the routes below.
+pub const TYPED_PATH_PARAMS: &[(&str, &[PathParam])] = &[
+    ("/items/:id", &[("id", parses::<u16>)]),
+    ("/items/trash/:id", &[("id", parses::<u16>)]),
+    ("/items/:id/restore", &[("id", parses::<u16>)]),
+    ("/items/:id/revisions", &[("id", parses::<u16>)]),
+    (
+        "/items/:id/revisions/:rev",
+        &[("id", parses::<u16>), ("rev", parses::<u32>)],
+    ),
+    (
+        "/items/:id/revisions/:rev/restore",
+        &[("id", parses::<u16>), ("rev", parses::<u32>)],
+    ),
+];
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.parse::<T>().is_ok()
+}
+
+pub fn create_app_from(state: &AppState) -> impl Endpoint {
+    let live = state.live.clone();
+    let tokens = state.tokens.clone();
+    let api_keys = state.api_keys.clone();
+
+    let admin = Route::new()
+        .at("/api-keys", get(list_api_keys).post(create_api_key))
+        .at("/api-keys/:id", poem::delete(revoke_api_key))
+        .at("/backup", post(create_backup))
+        .at("/backups", get(list_backups))
+        .at("/restore/:name", post(restore_backup))
+        .with(RequireScope::new(SCOPE_ADMIN));
+
     Route::new()
         .at("/health", get(health_check))
-        .at("/items", get(get_all_items).post(create))
-        .at("/items/:id", get(get_item).put(edit).delete(delete))
-        .with(JwtMiddleware::new("secret-key"))
+        .at("/health/live", get(liveness))
+        .at("/health/ready", get(readiness))
+        .at("/metrics", get(prometheus_metrics))
+        .at(
+            "/items",
+            get(get_all_items).post(create.with(RequireScope::new(SCOPE_ITEMS_WRITE))),
+        )
+        .at(
+            "/items/:id",
+            get(get_item)
+                .put(edit.with(RequireScope::new(SCOPE_ITEMS_WRITE)))
+                .delete(delete.with(RequireScope::new(SCOPE_ITEMS_WRITE))),
+        )
+        .at(
+            "/items/trash",
+            get(list_trash.with(RequireScope::new(SCOPE_ITEMS_WRITE))),
+        )
+        .at(
+            "/items/trash/:id",
+            poem::delete(purge_item.with(RequireScope::new(SCOPE_ADMIN))),
+        )
+        .at(
+            "/items/:id/restore",
+            post(restore_item.with(RequireScope::new(SCOPE_ITEMS_WRITE))),
+        )
+        .at("/items/:id/revisions", get(list_revisions))
+        .at("/items/:id/revisions/:rev", get(get_revision))
+        .at(
+            "/items/:id/revisions/:rev/restore",
+            post(restore_revision.with(RequireScope::new(SCOPE_ITEMS_WRITE))),
+        )
+        .at(
+            "/audit",
+            get(list_audit_entries.with(RequireScope::new(SCOPE_ADMIN))),
+        )
+        .at("/auth/refresh", post(refresh))
+        .at("/auth/logout", post(logout))
+        .nest("/admin", admin)
+        .with(
+            JwtMiddleware::new(live.current().settings.auth.secret.clone())
+                .with_token_store(tokens.clone())
+                .with_api_keys(api_keys.clone())
+                .with_settings(live.clone()),
+        )
+        .with(SignatureMiddleware::new(live.clone()))
+        .with(ClientCertMiddleware::new(state.peers.clone(), live.clone()))
+        .with(ErrorMiddleware)
+        .with(MetricsMiddleware::new(state.metrics.clone()))
+        .with(TracingMiddleware)
+        .data(live)
+        .data(tokens)
+        .data(api_keys)
+        .data(state.items.clone())
+        .data(state.audit.clone())
+        .data(state.backups.clone())
+        .data(state.metrics.clone())
 }
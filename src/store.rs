@@ -0,0 +1,1389 @@
+use crate::auth::now;
+use crate::config::DatabaseSettings;
+use crate::errors::ApiError;
+use crate::metrics::Metrics;
+use crate::oplog::{write_atomically, LogHeader, Op, OpLog};
+use crate::revisions::{Revision, RevisionLog};
+use crate::routes::Item;
+use crate::schema::{self, Step};
+use arc_swap::ArcSwapOption;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound(u16),
+    RevisionNotFound {
+        id: u16,
+        rev: u32,
+    },
+    /// The revision records a deletion, so there is nothing to restore.
+    DeletedRevision {
+        id: u16,
+        rev: u32,
+    },
+    /// `field` of the new value is already used by the item `id`.
+    Conflict {
+        field: String,
+        id: u16,
+    },
+    /// The data file parses but its contents are inconsistent.
+    Corrupt(String),
+    /// The data file was written by a newer version of the app.
+    UnsupportedVersion(u32),
+    /// Every item ID has been assigned.
+    IdsExhausted,
+    Io(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound(id) => write!(f, "Item with id {} not found", id),
+            StoreError::RevisionNotFound { id, rev } => {
+                write!(f, "Revision {} of item {} not found", rev, id)
+            }
+            StoreError::DeletedRevision { id, rev } => write!(
+                f,
+                "Revision {} of item {} is a deletion and cannot be restored",
+                rev, id
+            ),
+            StoreError::Conflict { field, id } => {
+                write!(
+                    f,
+                    "An item with the same {} already exists (id {})",
+                    field, id
+                )
+            }
+            StoreError::Corrupt(reason) => write!(f, "data file is corrupt: {}", reason),
+            StoreError::UnsupportedVersion(version) => write!(
+                f,
+                "data file version {} is newer than the supported version {}",
+                version,
+                schema::VERSION
+            ),
+            StoreError::IdsExhausted => write!(f, "no item IDs are left"),
+            StoreError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(Box::new(err))
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Io(Box::new(err))
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::NotFound(_) | StoreError::RevisionNotFound { .. } => {
+                ApiError::NotFound(err.to_string())
+            }
+            StoreError::DeletedRevision { .. } => ApiError::Conflict {
+                detail: err.to_string(),
+                conflicting_id: None,
+            },
+            StoreError::Conflict { id, .. } => ApiError::Conflict {
+                detail: err.to_string(),
+                conflicting_id: Some(id),
+            },
+            StoreError::Corrupt(_)
+            | StoreError::UnsupportedVersion(_)
+            | StoreError::IdsExhausted => ApiError::Storage(format!("Storage error: {}", err)),
+            StoreError::Io(err) => ApiError::Storage(format!("Storage error: {}", err)),
+        }
+    }
+}
+
+/// The item collection, persisted as a snapshot in the database file (a
+/// versioned JSON envelope holding the items, see `schema`) plus an
+/// operation log next to it (`<name>.log`).
+///
+/// Changes are appended to the log, so a write costs the same whatever the
+/// size of the catalog. Once `database.compact_after` items have been
+/// changed, the snapshot is rewritten and the log started afresh. The items
+/// are kept in memory, loaded on first use by reading the snapshot and
+/// replaying the log, and reloaded whenever either file is changed by
+/// someone else.
+///
+/// Writes are serialized through a lock and checked against the configured
+/// unique fields, and every change is kept as a revision of the item. Reads
+/// do not take the lock: they are served from an immutable copy of the
+/// items, which a write discards and the next read rebuilds. A read notices
+/// changes made by someone else within `database.refresh_interval_ms`.
+pub struct ItemStore {
+    path: PathBuf,
+    log: OpLog,
+    revisions: RevisionLog,
+    unique_fields: Vec<String>,
+    compact_after: usize,
+    refresh_interval_ms: u64,
+    backups: usize,
+    state: Mutex<Option<State>>,
+    cache: ArcSwapOption<Snapshot>,
+    opened: Instant,
+    recovery: Mutex<Option<Recovery>>,
+    metrics: Option<Metrics>,
+}
+
+/// The items as of some point, shared by the reads made since.
+struct Snapshot {
+    /// Every item, including those in the trash, in ID order.
+    items: Vec<Item>,
+    /// The files the items were read from or written to.
+    stamps: Stamps,
+    /// When the files were last seen unchanged, in milliseconds since the
+    /// store was opened.
+    checked: AtomicU64,
+}
+
+impl Snapshot {
+    fn find(&self, id: u16, live: bool) -> Result<&Item, StoreError> {
+        self.items
+            .binary_search_by_key(&id, |item| item.id)
+            .ok()
+            .map(|i| &self.items[i])
+            .filter(|item| item.is_live() == live)
+            .ok_or(StoreError::NotFound(id))
+    }
+}
+
+/// The items as of the last load or write, with what is derived from them.
+struct State {
+    items: BTreeMap<u16, Item>,
+    /// The number of items that are not in the trash.
+    live: usize,
+    /// The highest ID ever assigned. IDs are never reused, even once the
+    /// item holding one is purged, so that its history and audit trail
+    /// stay its own.
+    last_id: u16,
+    index: UniqueIndex,
+    /// The latest revision number of each item, read on first use.
+    revs: Option<HashMap<u16, u32>>,
+    /// The snapshot the log applies to.
+    header: LogHeader,
+    /// Whether the log on disk belongs to the snapshot. If it does not, the
+    /// next write starts a new log.
+    log_matches: bool,
+    /// Items changed since the last compaction.
+    pending: usize,
+    /// The files as they were after the last load or write.
+    stamps: Stamps,
+}
+
+impl State {
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::Put { item } => self.put(item),
+            Op::PutAll { items } => items.into_iter().for_each(|item| self.put(item)),
+            Op::Remove { id } => {
+                if let Some(removed) = self.items.remove(&id) {
+                    self.release(&removed);
+                }
+            }
+        }
+    }
+
+    fn put(&mut self, item: Item) {
+        // The previous value is released first, so that it cannot take the
+        // new value's keys with it when they are the same.
+        if let Some(previous) = self.items.remove(&item.id) {
+            self.release(&previous);
+        }
+        if item.is_live() {
+            self.live += 1;
+            self.index.insert(&item);
+        }
+        self.last_id = self.last_id.max(item.id);
+        self.items.insert(item.id, item);
+    }
+
+    /// Trashed items do not hold on to their unique values.
+    fn release(&mut self, item: &Item) {
+        if item.is_live() {
+            self.live -= 1;
+            self.index.remove(item);
+        }
+    }
+
+    /// The IDs of the next `count` items created.
+    fn next_ids(&self, count: usize) -> Result<std::ops::RangeInclusive<u16>, StoreError> {
+        let first = self.last_id.checked_add(1);
+        let last = u16::try_from(count)
+            .ok()
+            .and_then(|count| self.last_id.checked_add(count));
+
+        match (first, last) {
+            (Some(first), Some(last)) => Ok(first..=last),
+            _ => Err(StoreError::IdsExhausted),
+        }
+    }
+
+    fn find(&self, id: u16, live: bool) -> Result<&Item, StoreError> {
+        self.items
+            .get(&id)
+            .filter(|item| item.is_live() == live)
+            .ok_or(StoreError::NotFound(id))
+    }
+}
+
+/// What `ItemStore::recover` did about corrupt files.
+#[derive(Debug, Clone)]
+pub enum Recovery {
+    /// The operation log was set aside and the data file kept.
+    LogQuarantined {
+        reason: String,
+        quarantined: PathBuf,
+        lost: usize,
+    },
+    /// The data file was set aside and replaced with a backup, brought up
+    /// to date by replaying the `replayed` changes logged since.
+    Restored {
+        reason: String,
+        quarantined: PathBuf,
+        backup: PathBuf,
+        replayed: usize,
+        lost: usize,
+    },
+    /// The data file was set aside and, with no valid backup, replaced with
+    /// an empty one.
+    Emptied {
+        reason: String,
+        quarantined: PathBuf,
+        lost: usize,
+    },
+}
+
+impl Recovery {
+    /// The number of items whose latest change, as recorded in their
+    /// revisions, did not survive the recovery.
+    pub fn lost(&self) -> usize {
+        match self {
+            Recovery::LogQuarantined { lost, .. }
+            | Recovery::Restored { lost, .. }
+            | Recovery::Emptied { lost, .. } => *lost,
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::LogQuarantined {
+                reason,
+                quarantined,
+                ..
+            } => write!(
+                f,
+                "corrupt operation log ({}) moved to {}",
+                reason,
+                quarantined.display()
+            )?,
+            Recovery::Restored {
+                reason,
+                quarantined,
+                backup,
+                replayed,
+                ..
+            } => write!(
+                f,
+                "corrupt data file ({}) moved to {}; restored from {} and {} logged changes",
+                reason,
+                quarantined.display(),
+                backup.display(),
+                replayed
+            )?,
+            Recovery::Emptied {
+                reason,
+                quarantined,
+                ..
+            } => write!(
+                f,
+                "corrupt data file ({}) moved to {}; no valid backup, started empty",
+                reason,
+                quarantined.display()
+            )?,
+        }
+        match self.lost() {
+            0 => write!(f, "; no changes were lost"),
+            lost => write!(f, "; the latest changes to {} items were lost", lost),
+        }
+    }
+}
+
+/// The changes `ItemStore::migrate` would make to the files.
+#[derive(Debug)]
+pub struct MigrationPlan {
+    /// The version the data file is in, or `None` if it does not exist.
+    pub version: Option<u32>,
+    /// The migrations that would be applied.
+    pub steps: Vec<Step>,
+    /// Item changes in the operation log that would be folded in.
+    pub logged_changes: usize,
+    pub items: usize,
+}
+
+/// An item before and after a change; `None` where it does not exist.
+pub type Change = (Option<Item>, Option<Item>);
+
+/// Modification time and length of the snapshot and the log.
+type Stamps = [Option<(Option<SystemTime>, u64)>; 2];
+
+impl ItemStore {
+    pub fn new(settings: &DatabaseSettings) -> Self {
+        let path = PathBuf::from(settings.database_name());
+
+        Self {
+            log: OpLog::new(sibling(&path, ".log")),
+            path,
+            revisions: RevisionLog::new(&settings.revisions_file),
+            unique_fields: settings.unique_fields.clone(),
+            compact_after: settings.compact_after,
+            refresh_interval_ms: settings.refresh_interval_ms,
+            backups: settings.backups,
+            state: Mutex::new(None),
+            cache: ArcSwapOption::empty(),
+            opened: Instant::now(),
+            recovery: Mutex::new(None),
+            metrics: None,
+        }
+    }
+
+    /// Records the duration and outcome of file reads and writes, and the
+    /// item count, in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Runs `f` against the store on the blocking thread pool, so that a slow
+    /// disk holds up the requests waiting on it rather than a runtime worker.
+    pub async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&ItemStore) -> T + Send + 'static,
+    ) -> T {
+        let store = self.clone();
+        unblock(move || f(&store)).await
+    }
+
+    /// The items that are not in the trash.
+    pub fn all(&self) -> Result<Vec<Item>, StoreError> {
+        let snapshot = self.snapshot()?;
+        Ok(snapshot
+            .items
+            .iter()
+            .filter(|item| item.is_live())
+            .cloned()
+            .collect())
+    }
+
+    pub fn get(&self, id: u16) -> Result<Item, StoreError> {
+        self.snapshot()?.find(id, true).cloned()
+    }
+
+    /// The deleted items that have not been purged yet.
+    pub fn trash(&self) -> Result<Vec<Item>, StoreError> {
+        let snapshot = self.snapshot()?;
+        Ok(snapshot
+            .items
+            .iter()
+            .filter(|item| !item.is_live())
+            .cloned()
+            .collect())
+    }
+
+    pub fn create(&self, name: String) -> Result<Item, StoreError> {
+        self.write(|state| {
+            let id = *state.next_ids(1)?.start();
+            let item = Item::new(id, name);
+
+            state.index.check(&item)?;
+            self.commit(state, vec![Op::Put { item: item.clone() }])?;
+            self.revise(state, vec![(None, Some(item.clone()))]);
+
+            Ok(item)
+        })
+    }
+
+    /// Adds items in a single write. Nothing is written if any name is taken,
+    /// whether by a stored item or by an earlier item of the batch. IDs are
+    /// assigned as by `create`.
+    pub fn import(&self, names: Vec<String>) -> Result<Vec<Item>, StoreError> {
+        self.write(|state| {
+            let mut batch = UniqueIndex::new(self.unique_fields.clone());
+            let mut imported = Vec::with_capacity(names.len());
+            for (id, name) in state.next_ids(names.len())?.zip(names) {
+                let item = Item::new(id, name);
+
+                state.index.check(&item)?;
+                batch.check(&item)?;
+                batch.insert(&item);
+                imported.push(item);
+            }
+
+            self.commit(
+                state,
+                vec![Op::PutAll {
+                    items: imported.clone(),
+                }],
+            )?;
+            self.revise(
+                state,
+                imported
+                    .iter()
+                    .map(|item| (None, Some(item.clone())))
+                    .collect(),
+            );
+
+            Ok(imported)
+        })
+    }
+
+    /// Rewrites the data file in the current format, folding in the
+    /// operation log and creating the file when it is missing. Returns the
+    /// number of items it holds.
+    pub fn migrate(&self) -> Result<usize, StoreError> {
+        let mut guard = self.lock_state();
+
+        if !self.path.exists() {
+            *guard = Some(State {
+                stamps: self.stamps(),
+                ..self.empty_state()
+            });
+        }
+        let state = self.current(&mut guard)?;
+        self.compact(state)?;
+
+        Ok(state.items.len())
+    }
+
+    /// What `migrate` would do, without writing anything.
+    pub fn plan_migration(&self) -> Result<MigrationPlan, StoreError> {
+        let _guard = self.lock_state();
+
+        if !self.path.exists() {
+            return Ok(MigrationPlan {
+                version: None,
+                steps: Vec::new(),
+                logged_changes: 0,
+                items: 0,
+            });
+        }
+        let (state, version, steps) = self.read_files(false)?;
+
+        Ok(MigrationPlan {
+            version: Some(version),
+            steps,
+            logged_changes: state.pending,
+            items: state.items.len(),
+        })
+    }
+
+    /// Replaces the item, returning it as it was before and as it is now.
+    pub fn update(&self, id: u16, name: String) -> Result<(Item, Item), StoreError> {
+        self.write(|state| {
+            let previous = state.find(id, true)?.clone();
+            let item = Item::new(id, name);
+
+            state.index.check(&item)?;
+            self.commit(state, vec![Op::Put { item: item.clone() }])?;
+            self.revise(state, vec![(Some(previous.clone()), Some(item.clone()))]);
+
+            Ok((previous, item))
+        })
+    }
+
+    /// Moves the item to the trash, returning it as it was. Its unique
+    /// values are released, and it is kept until purged.
+    pub fn delete(&self, id: u16) -> Result<Item, StoreError> {
+        self.write(|state| {
+            let removed = state.find(id, true)?.clone();
+            let trashed = Item {
+                deleted_at: Some(now()),
+                ..removed.clone()
+            };
+
+            self.commit(state, vec![Op::Put { item: trashed }])?;
+            self.revise(state, vec![(Some(removed.clone()), None)]);
+
+            Ok(removed)
+        })
+    }
+
+    /// Takes the item out of the trash, returning it as it was in the trash
+    /// and as it is now.
+    pub fn undelete(&self, id: u16) -> Result<(Item, Item), StoreError> {
+        self.write(|state| {
+            let trashed = state.find(id, false)?.clone();
+            let item = Item::new(id, trashed.name.clone());
+
+            state.index.check(&item)?;
+            self.commit(state, vec![Op::Put { item: item.clone() }])?;
+            self.revise(state, vec![(None, Some(item.clone()))]);
+
+            Ok((trashed, item))
+        })
+    }
+
+    /// Removes an item from the trash for good. Its revisions are kept.
+    pub fn purge(&self, id: u16) -> Result<Item, StoreError> {
+        self.write(|state| {
+            let purged = state.find(id, false)?.clone();
+            self.commit(state, vec![Op::Remove { id }])?;
+
+            Ok(purged)
+        })
+    }
+
+    /// Purges the items that have been in the trash for at least `retention`
+    /// seconds, returning them.
+    pub fn purge_expired(&self, retention: u64) -> Result<Vec<Item>, StoreError> {
+        self.write(|state| {
+            let expires_before = now().saturating_sub(retention);
+            let purged: Vec<Item> = state
+                .items
+                .values()
+                .filter(|item| {
+                    item.deleted_at
+                        .is_some_and(|deleted_at| deleted_at <= expires_before)
+                })
+                .cloned()
+                .collect();
+
+            if !purged.is_empty() {
+                let ops = purged
+                    .iter()
+                    .map(|item| Op::Remove { id: item.id })
+                    .collect();
+                self.commit(state, ops)?;
+            }
+            Ok(purged)
+        })
+    }
+
+    /// The revisions of an item, oldest first, including those of a deleted
+    /// or purged item. IDs are never reused, so the history is the item's
+    /// alone. An item that has not changed since history started has a
+    /// single revision holding its current value.
+    pub fn revisions(&self, id: u16) -> Result<Vec<Revision>, StoreError> {
+        self.history(id, || self.get(id))
+    }
+
+    pub fn revision(&self, id: u16, rev: u32) -> Result<Revision, StoreError> {
+        find_revision(self.revisions(id)?, id, rev)
+    }
+
+    /// The items as they were at `timestamp`, in ID order.
+    pub fn as_of(&self, timestamp: u64) -> Result<Vec<Item>, StoreError> {
+        let current = self.snapshot()?;
+        let past = self.revisions.as_of(timestamp)?;
+
+        // Items without a history have not changed since it started.
+        let mut items: Vec<Item> = current
+            .items
+            .iter()
+            .filter(|item| item.is_live() && !past.contains_key(&item.id))
+            .cloned()
+            .collect();
+        items.extend(
+            past.into_values()
+                .filter_map(|revision| revision.and_then(|revision| revision.item)),
+        );
+        items.sort_by_key(|item| item.id);
+
+        Ok(items)
+    }
+
+    /// Sets the item back to the value it had at revision `rev`, taking it
+    /// out of the trash or recreating it if it was purged since. Returns the
+    /// replaced value, if any, and the restored item; the restore itself
+    /// becomes a new revision.
+    pub fn restore(&self, id: u16, rev: u32) -> Result<(Option<Item>, Item), StoreError> {
+        self.write(|state| {
+            let revisions = self.history(id, || state.find(id, true).cloned())?;
+            let item = find_revision(revisions, id, rev)?
+                .item
+                .ok_or(StoreError::DeletedRevision { id, rev })?;
+            let previous = state.items.get(&id).cloned();
+
+            state.index.check(&item)?;
+            self.commit(state, vec![Op::Put { item: item.clone() }])?;
+            self.revise(state, vec![(previous.clone(), Some(item.clone()))]);
+
+            Ok((previous, item))
+        })
+    }
+
+    /// Verifies that the data file and operation log can be read and hold a
+    /// consistent item list, returning the number of items.
+    pub fn check(&self) -> Result<usize, StoreError> {
+        self.read(|state| Ok(state.items.len()))
+    }
+
+    /// Checks the files and, if they are corrupt, sets the corrupt one aside.
+    /// A corrupt data file is replaced with the newest valid backup, brought
+    /// up to date with the changes logged since, or with an empty one if
+    /// there is none; a corrupt operation log is dropped along with the
+    /// changes it held. Returns what was done, which `recovery` reports from
+    /// then on.
+    pub fn recover(&self) -> Result<Option<Recovery>, StoreError> {
+        let mut guard = self.lock_state();
+        let reason = match self.current(&mut guard) {
+            Ok(_) => return Ok(None),
+            Err(StoreError::Corrupt(reason)) => reason,
+            Err(err) => return Err(err),
+        };
+
+        let suffix = format!(".corrupt-{}", now());
+        let quarantine_log = || -> Result<PathBuf, StoreError> {
+            let quarantined = sibling(self.log.path(), &suffix);
+            fs::rename(self.log.path(), &quarantined)?;
+            Ok(quarantined)
+        };
+        let mut recovery = match decode(&fs::read(&self.path)?) {
+            Ok(_) => Recovery::LogQuarantined {
+                reason,
+                quarantined: quarantine_log()?,
+                lost: 0,
+            },
+            Err(StoreError::Corrupt(_)) => {
+                let quarantined = sibling(&self.path, &suffix);
+                fs::rename(&self.path, &quarantined)?;
+
+                let (data, recovery) = match self.newest_valid_backup() {
+                    Some((number, decoded)) => {
+                        let (restored, replayed) = self.catch_up(number, decoded, &suffix)?;
+                        // The backup may predate IDs handed out since.
+                        let items: Vec<&Item> = restored.items.values().collect();
+                        let last_id = restored.last_id.max(self.revised_id());
+                        let recovery = Recovery::Restored {
+                            reason,
+                            quarantined,
+                            backup: self.backup_path(number),
+                            replayed,
+                            lost: 0,
+                        };
+                        (schema::encode(&items, last_id)?, recovery)
+                    }
+                    None => {
+                        // The revisions still know which IDs were handed out.
+                        let last_id = self.revised_id();
+                        if self.log.path().exists() {
+                            quarantine_log()?;
+                        }
+                        let recovery = Recovery::Emptied {
+                            reason,
+                            quarantined,
+                            lost: 0,
+                        };
+                        (schema::encode(&[], last_id)?, recovery)
+                    }
+                };
+                write_atomically(&self.path, &data)?;
+                // Any logged changes are in the new data file, or lost.
+                self.log.start(&LogHeader::for_snapshot(&data), &[])?;
+                recovery
+            }
+            Err(err) => return Err(err),
+        };
+
+        *guard = None;
+        let state = self.current(&mut guard)?;
+        let count = self.lost(state);
+        match &mut recovery {
+            Recovery::LogQuarantined { lost, .. }
+            | Recovery::Restored { lost, .. }
+            | Recovery::Emptied { lost, .. } => *lost = count,
+        }
+        *self.recovery.lock().unwrap_or_else(|err| err.into_inner()) = Some(recovery.clone());
+        Ok(Some(recovery))
+    }
+
+    /// What `recover` did, if it found the files corrupt.
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Waits for a write in progress, if any, and compacts the operation log
+    /// into the data file, so that the data file alone is up to date.
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let mut guard = self.lock_state();
+
+        match guard.as_mut() {
+            Some(state) if state.pending > 0 && state.stamps == self.stamps() => {
+                self.compact(state)
+            }
+            _ => {
+                if self.path.exists() {
+                    File::open(&self.path)?.sync_all()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes every item, including those in the trash, to `path` as a data
+    /// file of the current version, and returns how many there are. The
+    /// write lock is held throughout, so the copy never holds part of a
+    /// change.
+    pub fn write_copy(&self, path: &Path) -> Result<usize, StoreError> {
+        self.read(|state| {
+            let items: Vec<&Item> = state.items.values().collect();
+            write_atomically(path, &schema::encode(&items, state.last_id)?)?;
+            Ok(items.len())
+        })
+    }
+
+    /// Replaces every item with those of `data`, a data file of this or an
+    /// older version, and returns the `(before, after)` pair of each item it
+    /// changed. The data file being replaced is backed up first, and each
+    /// change is kept as a revision. Items of `data` that clash on a unique
+    /// field are a conflict, and nothing is replaced.
+    pub fn replace(&self, data: &[u8]) -> Result<Vec<Change>, StoreError> {
+        let decoded = decode(data)?;
+
+        self.write(|state| {
+            // Restoring an older backup does not make IDs assigned since
+            // available again.
+            let mut replaced = self.empty_state();
+            replaced.last_id = state.last_id.max(decoded.last_id);
+            for item in decoded.items {
+                if item.is_live() {
+                    replaced.index.check(&item)?;
+                }
+                replaced.put(item);
+            }
+
+            let ids: BTreeSet<u16> = state
+                .items
+                .keys()
+                .chain(replaced.items.keys())
+                .copied()
+                .collect();
+            let changes: Vec<_> = ids
+                .into_iter()
+                .map(|id| (state.items.get(&id), replaced.items.get(&id)))
+                .filter(|(before, after)| before != after)
+                .map(|(before, after)| (before.cloned(), after.cloned()))
+                .collect();
+
+            replaced.revs = state.revs.take();
+            self.compact(&mut replaced)?;
+            *state = replaced;
+            self.count(state);
+            self.revise(state, changes.clone());
+
+            Ok(changes)
+        })
+    }
+
+    /// The revisions of an item, or a single revision holding `current` if
+    /// it has no history.
+    fn history(
+        &self,
+        id: u16,
+        current: impl FnOnce() -> Result<Item, StoreError>,
+    ) -> Result<Vec<Revision>, StoreError> {
+        let history = self.revisions.history(id)?;
+        if !history.is_empty() {
+            return Ok(history);
+        }
+
+        Ok(vec![Revision {
+            item_id: id,
+            rev: 1,
+            timestamp: 0,
+            item: Some(current()?),
+        }])
+    }
+
+    /// The items to serve reads from, without taking the lock unless they
+    /// changed since they were last read.
+    fn snapshot(&self) -> Result<Arc<Snapshot>, StoreError> {
+        if let Some(snapshot) = self.cache.load_full() {
+            if self.is_fresh(&snapshot) {
+                return Ok(snapshot);
+            }
+        }
+
+        let mut guard = self.lock_state();
+        let state = self.current(&mut guard)?;
+
+        // Another read may have rebuilt it while this one waited.
+        if let Some(snapshot) = self.cache.load_full() {
+            if snapshot.stamps == state.stamps {
+                return Ok(snapshot);
+            }
+        }
+
+        let snapshot = Arc::new(Snapshot {
+            items: state.items.values().cloned().collect(),
+            stamps: state.stamps,
+            checked: AtomicU64::new(self.elapsed_ms()),
+        });
+        self.cache.store(Some(snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    /// Whether the files are unchanged since `snapshot` was taken, looking
+    /// at them at most once per refresh interval.
+    fn is_fresh(&self, snapshot: &Snapshot) -> bool {
+        let now = self.elapsed_ms();
+        let checked = snapshot.checked.load(Ordering::Relaxed);
+        if now.saturating_sub(checked) < self.refresh_interval_ms {
+            return true;
+        }
+
+        let fresh = snapshot.stamps == self.stamps();
+        if fresh {
+            snapshot.checked.store(now, Ordering::Relaxed);
+        }
+        fresh
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.opened.elapsed().as_millis() as u64
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&State) -> Result<T, StoreError>) -> Result<T, StoreError> {
+        let mut guard = self.lock_state();
+        f(self.current(&mut guard)?)
+    }
+
+    /// Runs `f` under the write lock. The items are reloaded from disk on
+    /// next use if `f` fails to write them.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut State) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let mut guard = self.lock_state();
+        let result = f(self.current(&mut guard)?);
+
+        if let Err(StoreError::Io(_)) = result {
+            *guard = None;
+            self.cache.store(None);
+        }
+        result
+    }
+
+    /// The loaded state, reloaded first if the files were changed by
+    /// someone else since it was loaded or last written.
+    fn current<'a>(
+        &self,
+        guard: &'a mut MutexGuard<'_, Option<State>>,
+    ) -> Result<&'a mut State, StoreError> {
+        let stamps = self.stamps();
+        if guard.as_ref().is_some_and(|state| state.stamps != stamps) {
+            **guard = None;
+        }
+
+        match guard.take() {
+            Some(state) => Ok(guard.insert(state)),
+            None => {
+                self.cache.store(None);
+                let state = self.load()?;
+                self.count(&state);
+                Ok(guard.insert(state))
+            }
+        }
+    }
+
+    /// Reads the snapshot and replays the log on top of it. A log left by
+    /// an interrupted write is repaired. A log written for another snapshot
+    /// is ignored if the data file already holds its changes, as after a
+    /// crash during compaction, and is corruption otherwise, so that its
+    /// changes are never dropped without notice.
+    ///
+    /// A data file written in an older version is upgraded and rewritten.
+    fn load(&self) -> Result<State, StoreError> {
+        self.observe("read", || {
+            let (mut state, version, _) = self.read_files(true)?;
+
+            if version < schema::VERSION {
+                self.compact(&mut state)?;
+                tracing::info!(
+                    from = version,
+                    to = schema::VERSION,
+                    "upgraded the data file"
+                );
+            }
+            Ok(state)
+        })
+    }
+
+    /// The state held by the files, with the version the data file was
+    /// written in and the migrations applied to it. A cut short last line
+    /// of the log is truncated if `repair` is set, and skipped otherwise.
+    fn read_files(&self, repair: bool) -> Result<(State, u32, Vec<Step>), StoreError> {
+        let data = fs::read(&self.path)?;
+        let decoded = decode(&data)?;
+
+        let mut state = self.empty_state();
+        state.header = LogHeader::for_snapshot(&data);
+        state.last_id = decoded.last_id;
+        for item in decoded.items.iter().cloned() {
+            state.put(item);
+        }
+
+        let replay = if repair {
+            self.log.replay(&state.header, decoded.version)?
+        } else {
+            self.log.inspect(&state.header, decoded.version)?
+        };
+        if !replay.matches {
+            // Operations are idempotent, so a log whose changes the snapshot
+            // already holds leaves it as it is.
+            let mut replayed = self.empty_state();
+            decoded
+                .items
+                .into_iter()
+                .for_each(|item| replayed.put(item));
+            let changes = replay.ops.len();
+            replay.ops.into_iter().for_each(|op| replayed.apply(op));
+
+            if replayed.items != state.items {
+                return Err(StoreError::Corrupt(format!(
+                    "{} holds {} changes to another version of the data file",
+                    self.log.path().display(),
+                    changes
+                )));
+            }
+        } else {
+            for op in replay.ops {
+                state.pending += op.item_count();
+                state.apply(op);
+            }
+        }
+        state.log_matches = replay.matches;
+        state.stamps = self.stamps();
+
+        Ok((state, decoded.version, decoded.steps))
+    }
+
+    fn empty_state(&self) -> State {
+        State {
+            items: BTreeMap::new(),
+            live: 0,
+            last_id: 0,
+            index: UniqueIndex::new(self.unique_fields.clone()),
+            revs: None,
+            header: LogHeader::for_snapshot(b""),
+            log_matches: false,
+            pending: 0,
+            stamps: [None, None],
+        }
+    }
+
+    /// Appends `ops` to the log and applies them, compacting when enough
+    /// changes have piled up.
+    fn commit(&self, state: &mut State, ops: Vec<Op>) -> Result<(), StoreError> {
+        self.observe("write", || {
+            if state.log_matches {
+                self.log.append(&ops)
+            } else {
+                self.log.start(&state.header, &ops)
+            }
+        })?;
+        state.log_matches = true;
+
+        for op in ops {
+            state.pending += op.item_count();
+            state.apply(op);
+        }
+        state.stamps = self.stamps();
+        self.cache.store(None);
+        self.count(state);
+
+        if state.pending >= self.compact_after {
+            // The changes are safe in the log; compaction is retried on the
+            // next write.
+            if let Err(err) = self.compact(state) {
+                tracing::warn!("failed to compact the operation log: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the items as a new snapshot and starts an empty log for it.
+    /// A crash in between leaves the old log, which no longer matches the
+    /// snapshot but whose changes it holds, so it is ignored.
+    fn compact(&self, state: &mut State) -> Result<(), StoreError> {
+        self.observe("compact", || {
+            let items: Vec<&Item> = state.items.values().collect();
+            let data = schema::encode(&items, state.last_id)?;
+            self.back_up()?;
+            write_atomically(&self.path, &data)?;
+
+            let header = LogHeader::for_snapshot(&data);
+            self.log.start(&header, &[])?;
+
+            state.header = header;
+            state.log_matches = true;
+            state.pending = 0;
+            state.stamps = self.stamps();
+            self.cache.store(None);
+            Ok(())
+        })
+    }
+
+    /// Keeps the data file about to be replaced as backup 1, along with the
+    /// log of the changes made to it since, moving older backups up one
+    /// place and dropping the oldest.
+    fn back_up(&self) -> Result<(), StoreError> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+
+        for number in (1..self.backups).rev() {
+            let older = self.backup_path(number);
+            if older.exists() {
+                fs::rename(&older, self.backup_path(number + 1))?;
+            }
+            // A log is only ever kept next to the snapshot it applies to.
+            let (older, newer) = (
+                self.backup_log_path(number),
+                self.backup_log_path(number + 1),
+            );
+            if older.exists() {
+                fs::rename(&older, &newer)?;
+            } else if newer.exists() {
+                fs::remove_file(&newer)?;
+            }
+        }
+
+        let data = fs::read(&self.path)?;
+        fs::write(self.backup_path(1), &data)?;
+        let log = self.backup_log_path(1);
+        if self.log.header() == Some(LogHeader::for_snapshot(&data)) {
+            fs::copy(self.log.path(), &log)?;
+        } else if log.exists() {
+            fs::remove_file(&log)?;
+        }
+        Ok(())
+    }
+
+    fn backup_path(&self, number: usize) -> PathBuf {
+        sibling(&self.path, &format!(".bak.{}", number))
+    }
+
+    fn backup_log_path(&self, number: usize) -> PathBuf {
+        sibling(&self.path, &format!(".bak.{}.log", number))
+    }
+
+    /// The number of the newest backup that can be read, and its contents.
+    fn newest_valid_backup(&self) -> Option<(usize, schema::Decoded)> {
+        (1..=self.backups).find_map(|number| {
+            let decoded = decode(&fs::read(self.backup_path(number)).ok()?).ok()?;
+            Some((number, decoded))
+        })
+    }
+
+    /// The items of backup `number`, `decoded`, with the changes logged
+    /// since replayed on top: the logs kept with it and each newer backup,
+    /// oldest first, then the current log. Each log holds full items, so
+    /// replaying one its snapshot already holds changes nothing. A log that
+    /// is missing or cannot be read is skipped, and its changes lost; the
+    /// current log is then moved aside with `suffix`. Returns the items and
+    /// the number of changes replayed.
+    fn catch_up(
+        &self,
+        number: usize,
+        decoded: schema::Decoded,
+        suffix: &str,
+    ) -> Result<(State, usize), StoreError> {
+        let mut state = self.empty_state();
+        state.last_id = decoded.last_id;
+        decoded.items.into_iter().for_each(|item| state.put(item));
+
+        let logs = (1..=number)
+            .rev()
+            .map(|number| (self.backup_log_path(number), Some(self.backup_path(number))))
+            .chain([(self.log.path().to_path_buf(), None)]);
+        let mut replayed = 0;
+        for (log, snapshot) in logs {
+            if !log.exists() {
+                continue;
+            }
+            // Operations are written in the version of their snapshot.
+            let version = snapshot
+                .as_ref()
+                .and_then(|snapshot| decode(&fs::read(snapshot).ok()?).ok())
+                .map_or(schema::VERSION, |decoded| decoded.version);
+            match OpLog::new(&log).inspect(&state.header, version) {
+                Ok(replay) => {
+                    replayed += replay.ops.iter().map(Op::item_count).sum::<usize>();
+                    replay.ops.into_iter().for_each(|op| state.apply(op));
+                }
+                Err(err) => {
+                    tracing::warn!(log = %log.display(), "skipped an unreadable log: {}", err);
+                    if snapshot.is_none() {
+                        fs::rename(&log, sibling(&log, suffix))?;
+                    }
+                }
+            }
+        }
+        Ok((state, replayed))
+    }
+
+    /// The number of items that do not hold the value their latest revision
+    /// recorded, having lost a change. An unreadable history counts as
+    /// empty.
+    fn lost(&self, state: &State) -> usize {
+        self.revisions
+            .as_of(u64::MAX)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(id, revision)| {
+                let recorded = revision
+                    .as_ref()
+                    .and_then(|revision| revision.item.as_ref());
+                let current = state.items.get(id).filter(|item| item.is_live());
+                recorded != current
+            })
+            .count()
+    }
+
+    /// The highest item ID in the revision history, for when the data file
+    /// that recorded the highest ID assigned is lost. An unreadable history
+    /// counts as empty.
+    fn revised_id(&self) -> u16 {
+        self.revisions
+            .all()
+            .unwrap_or_default()
+            .iter()
+            .map(|revision| revision.item_id)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Appends a revision for each `(before, after)` change. An item changed
+    /// for the first time since history started first gets a revision with
+    /// its previous value.
+    ///
+    /// The change is already stored by then, so a failure is logged and
+    /// counted rather than reported, as for a failed audit entry: the write
+    /// succeeds, and the item's history misses the revision.
+    fn revise(&self, state: &mut State, changes: Vec<Change>) {
+        let ids: Vec<u16> = changes
+            .iter()
+            .filter_map(|(before, after)| after.as_ref().or(before.as_ref()))
+            .map(|item| item.id)
+            .collect();
+
+        if let Err(err) = self.observe("revise", || self.append_revisions(state, changes)) {
+            tracing::error!(item_ids = ?ids, "failed to record revisions: {}", err);
+        }
+    }
+
+    fn append_revisions(&self, state: &mut State, changes: Vec<Change>) -> Result<(), StoreError> {
+        let mut latest = match state.revs.take() {
+            Some(latest) => latest,
+            None => self
+                .revisions
+                .all()?
+                .into_iter()
+                .map(|revision| (revision.item_id, revision.rev))
+                .collect(),
+        };
+
+        let timestamp = now();
+        let mut revisions = Vec::with_capacity(changes.len());
+        for (before, after) in changes {
+            let Some(id) = after.as_ref().or(before.as_ref()).map(|item| item.id) else {
+                continue;
+            };
+
+            let rev = latest.entry(id).or_insert(0);
+            if *rev == 0 && before.is_some() {
+                *rev = 1;
+                revisions.push(Revision {
+                    item_id: id,
+                    rev: 1,
+                    timestamp: 0,
+                    item: before,
+                });
+            }
+
+            *rev += 1;
+            revisions.push(Revision {
+                item_id: id,
+                rev: *rev,
+                timestamp,
+                item: after,
+            });
+        }
+
+        // The numbers are only kept once the revisions are on disk.
+        self.revisions.append(&revisions)?;
+        state.revs = Some(latest);
+        Ok(())
+    }
+
+    fn stamps(&self) -> Stamps {
+        [stamp(&self.path), stamp(self.log.path())]
+    }
+
+    fn observe<T>(
+        &self,
+        operation: &str,
+        f: impl FnOnce() -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let started = Instant::now();
+        let result = f();
+
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_storage(operation, started.elapsed(), result.is_ok());
+        }
+        result
+    }
+
+    fn count(&self, state: &State) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_item_count(state.live);
+        }
+    }
+
+    /// The state lock doubles as the store's write lock.
+    fn lock_state(&self) -> MutexGuard<'_, Option<State>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Runs `f` on the blocking thread pool. A panic in `f` is resumed in the
+/// caller.
+pub async fn unblock<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// Decodes a data file, which must not hold the same ID twice.
+fn decode(data: &[u8]) -> Result<schema::Decoded, StoreError> {
+    let decoded = schema::decode(data)?;
+
+    let mut ids = HashSet::new();
+    if let Some(item) = decoded.items.iter().find(|item| !ids.insert(item.id)) {
+        return Err(StoreError::Corrupt(format!(
+            "duplicate item id {}",
+            item.id
+        )));
+    }
+    Ok(decoded)
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn find_revision(revisions: Vec<Revision>, id: u16, rev: u32) -> Result<Revision, StoreError> {
+    revisions
+        .into_iter()
+        .find(|revision| revision.rev == rev)
+        .ok_or(StoreError::RevisionNotFound { id, rev })
+}
+
+fn stamp(path: &Path) -> Option<(Option<SystemTime>, u64)> {
+    fs::metadata(path)
+        .ok()
+        .map(|meta| (meta.modified().ok(), meta.len()))
+}
+
+/// Normalized values of the unique fields, mapped to the item holding them.
+struct UniqueIndex {
+    fields: Vec<String>,
+    values: HashMap<String, HashMap<String, u16>>,
+}
+
+impl UniqueIndex {
+    fn new(fields: Vec<String>) -> Self {
+        Self {
+            fields,
+            values: HashMap::new(),
+        }
+    }
+
+    fn check(&self, item: &Item) -> Result<(), StoreError> {
+        for (field, value) in self.keys(item) {
+            let existing = self
+                .values
+                .get(&field)
+                .and_then(|values| values.get(&value));
+
+            if let Some(&id) = existing.filter(|&&id| id != item.id) {
+                return Err(StoreError::Conflict { field, id });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, item: &Item) {
+        for (field, value) in self.keys(item) {
+            self.values.entry(field).or_default().insert(value, item.id);
+        }
+    }
+
+    /// Releases the item's values, unless another item holds them.
+    fn remove(&mut self, item: &Item) {
+        for (field, value) in self.keys(item) {
+            if let Some(values) = self.values.get_mut(&field) {
+                if values.get(&value) == Some(&item.id) {
+                    values.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// The normalized value of every unique field the item has.
+    fn keys(&self, item: &Item) -> Vec<(String, String)> {
+        let Ok(Value::Object(fields)) = serde_json::to_value(item) else {
+            return Vec::new();
+        };
+
+        self.fields
+            .iter()
+            .filter_map(|field| {
+                let value = match fields.get(field)? {
+                    Value::String(value) => normalize(value),
+                    value => value.to_string(),
+                };
+                Some((field.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Case-insensitive, compatibility-normalized form of a value, with runs of
+/// whitespace collapsed, so that "Zelda:  Tears" and "ZELDA: tears" collide.
+pub fn normalize(value: &str) -> String {
+    value
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,272 @@
+use crate::config::TlsSettings;
+use poem::http::uri::Scheme;
+use poem::listener::{Acceptor, Listener};
+use poem::web::{LocalAddr, RemoteAddr};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{BufReader, Error as IoError, ErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Subjects of the verified client certificates of currently open TLS
+/// connections, keyed by the peer address that poem reports as the request's
+/// remote address.
+#[derive(Clone, Default)]
+pub struct PeerIdentities {
+    peers: Arc<Mutex<HashMap<SocketAddr, String>>>,
+}
+
+impl PeerIdentities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<String> {
+        self.lock().get(addr).cloned()
+    }
+
+    fn insert(&self, addr: SocketAddr, subject: String) {
+        self.lock().insert(addr, subject);
+    }
+
+    fn remove(&self, addr: &SocketAddr) {
+        self.lock().remove(addr);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, String>> {
+        self.peers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Builds the rustls server configuration from the certificate, key and
+/// optional client CA files named in `settings`.
+pub fn server_config(settings: &TlsSettings) -> IoResult<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(&settings.cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key =
+        rustls_pemfile::private_key(&mut BufReader::new(std::fs::File::open(&settings.key)?))?
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "no private key found"))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &settings.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(client_ca)?))
+            {
+                roots.add(cert?).map_err(IoError::other)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if settings.require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            };
+            builder.with_client_cert_verifier(verifier.map_err(IoError::other)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(IoError::other)?;
+    config.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
+
+    Ok(config)
+}
+
+/// Identity carried by a DER certificate: its subject common name, or the
+/// full distinguished name when it has none.
+pub fn certificate_subject(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let subject = cert.subject();
+
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string);
+
+    common_name.or_else(|| Some(subject.to_string()))
+}
+
+/// Wraps a listener with TLS, recording the subject of each verified client
+/// certificate in `PeerIdentities` for as long as the connection is open.
+pub struct TlsListener<L> {
+    inner: L,
+    config: Arc<ServerConfig>,
+    peers: PeerIdentities,
+}
+
+impl<L> TlsListener<L> {
+    pub fn new(inner: L, config: ServerConfig, peers: PeerIdentities) -> Self {
+        Self {
+            inner,
+            config: Arc::new(config),
+            peers,
+        }
+    }
+}
+
+impl<L: Listener> Listener for TlsListener<L> {
+    type Acceptor = TlsAcceptorImpl<L::Acceptor>;
+
+    async fn into_acceptor(self) -> IoResult<Self::Acceptor> {
+        Ok(TlsAcceptorImpl {
+            inner: self.inner.into_acceptor().await?,
+            acceptor: TlsAcceptor::from(self.config),
+            peers: self.peers,
+        })
+    }
+}
+
+pub struct TlsAcceptorImpl<A> {
+    inner: A,
+    acceptor: TlsAcceptor,
+    peers: PeerIdentities,
+}
+
+impl<A: Acceptor> Acceptor for TlsAcceptorImpl<A> {
+    type Io = PeerStream<A::Io>;
+
+    fn local_addr(&self) -> Vec<LocalAddr> {
+        self.inner.local_addr()
+    }
+
+    async fn accept(&mut self) -> IoResult<(Self::Io, LocalAddr, RemoteAddr, Scheme)> {
+        let (stream, local_addr, remote_addr, _) = self.inner.accept().await?;
+        let peer_addr = remote_addr.as_socket_addr().copied();
+        let peers = self.peers.clone();
+        let handshake = self.acceptor.accept(stream);
+
+        // The handshake is driven lazily by the first read or write so that a
+        // slow client cannot hold up the accept loop. It finishes before any
+        // request bytes are read, so the subject is registered in time.
+        let handshake = async move {
+            let stream = handshake.await?;
+            if let Some(addr) = peer_addr {
+                let subject = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(|cert| certificate_subject(cert));
+                if let Some(subject) = subject {
+                    peers.insert(addr, subject);
+                }
+            }
+            Ok(stream)
+        };
+
+        let stream = PeerStream {
+            state: State::Handshaking(Box::pin(handshake)),
+            peer_addr,
+            peers: self.peers.clone(),
+        };
+
+        Ok((stream, local_addr, remote_addr, Scheme::HTTPS))
+    }
+}
+
+type Handshake<S> = Pin<Box<dyn Future<Output = IoResult<TlsStream<S>>> + Send>>;
+
+enum State<S> {
+    Handshaking(Handshake<S>),
+    Ready(Box<TlsStream<S>>),
+    Failed,
+}
+
+/// A server-side TLS stream that forgets its peer's identity when dropped.
+pub struct PeerStream<S> {
+    state: State<S>,
+    peer_addr: Option<SocketAddr>,
+    peers: PeerIdentities,
+}
+
+impl<S> PeerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<&mut TlsStream<S>>> {
+        if let State::Handshaking(handshake) = &mut self.state {
+            match handshake.as_mut().poll(cx) {
+                Poll::Ready(Ok(stream)) => self.state = State::Ready(Box::new(stream)),
+                Poll::Ready(Err(err)) => {
+                    self.state = State::Failed;
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match &mut self.state {
+            State::Ready(stream) => Poll::Ready(Ok(stream)),
+            _ => Poll::Ready(Err(IoError::new(
+                ErrorKind::InvalidData,
+                "TLS handshake failed",
+            ))),
+        }
+    }
+}
+
+impl<S> Drop for PeerStream<S> {
+    fn drop(&mut self) {
+        if let Some(addr) = &self.peer_addr {
+            self.peers.remove(addr);
+        }
+    }
+}
+
+impl<S> AsyncRead for PeerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        match self.get_mut().poll_stream(cx) {
+            Poll::Ready(Ok(stream)) => Pin::new(stream).poll_read(cx, buf),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> AsyncWrite for PeerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        match self.get_mut().poll_stream(cx) {
+            Poll::Ready(Ok(stream)) => Pin::new(stream).poll_write(cx, buf),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut().poll_stream(cx) {
+            Poll::Ready(Ok(stream)) => Pin::new(stream).poll_flush(cx),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut().poll_stream(cx) {
+            Poll::Ready(Ok(stream)) => Pin::new(stream).poll_shutdown(cx),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
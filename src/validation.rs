@@ -0,0 +1,230 @@
+use crate::config::FieldRulesOverride;
+use crate::errors::{ApiError, FieldError};
+use crate::reload::LiveSettings;
+use poem::web::Json;
+use poem::{FromRequest, Request, RequestBody};
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Rules a single JSON string field must satisfy.
+#[derive(Debug, Clone)]
+pub struct FieldRules {
+    pub required: bool,
+    /// Strip leading and trailing whitespace before checking the other rules.
+    pub trim: bool,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// The whole value must match this pattern.
+    pub pattern: Option<Regex>,
+    /// The message for a value that does not match `pattern`, describing
+    /// what it allows rather than showing the pattern itself.
+    pub pattern_message: Option<String>,
+}
+
+impl FieldRules {
+    pub fn new() -> Self {
+        Self {
+            required: false,
+            trim: false,
+            min_length: None,
+            max_length: None,
+            pattern: None,
+            pattern_message: None,
+        }
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn trim(mut self) -> Self {
+        self.trim = true;
+        self
+    }
+
+    pub fn length(mut self, min: usize, max: usize) -> Self {
+        self.min_length = Some(min);
+        self.max_length = Some(max);
+        self
+    }
+
+    pub fn pattern(mut self, pattern: &str, message: &str) -> Self {
+        self.pattern = Some(Regex::new(pattern).expect("built-in validation pattern is valid"));
+        self.pattern_message = Some(message.to_string());
+        self
+    }
+
+    fn apply(&self, rules: &FieldRulesOverride) -> Result<Self, regex::Error> {
+        Ok(Self {
+            required: rules.required.unwrap_or(self.required),
+            trim: rules.trim.unwrap_or(self.trim),
+            min_length: rules.min_length.or(self.min_length),
+            max_length: rules.max_length.or(self.max_length),
+            pattern: match &rules.pattern {
+                Some(pattern) => Some(Regex::new(pattern)?),
+                None => self.pattern.clone(),
+            },
+            // The built-in message describes the built-in pattern only.
+            pattern_message: match &rules.pattern {
+                Some(_) => rules.pattern_message.clone(),
+                None => rules
+                    .pattern_message
+                    .clone()
+                    .or_else(|| self.pattern_message.clone()),
+            },
+        })
+    }
+
+    /// Checks `value`, trimming it in place when asked to.
+    fn check(&self, field: &str, value: Option<&mut Value>, errors: &mut Vec<FieldError>) {
+        let value = match value {
+            None | Some(Value::Null) => {
+                if self.required {
+                    errors.push(FieldError::new(field, "is required"));
+                }
+                return;
+            }
+            Some(Value::String(value)) => value,
+            Some(_) => {
+                errors.push(FieldError::new(field, "must be a string"));
+                return;
+            }
+        };
+
+        if self.trim {
+            *value = value.trim().to_string();
+        }
+
+        let length = value.chars().count();
+        if self.required && length == 0 {
+            errors.push(FieldError::new(field, "must not be blank"));
+            return;
+        }
+        if let Some(min) = self.min_length.filter(|min| length < *min) {
+            errors.push(FieldError::new(
+                field,
+                format!("must be at least {} characters long", min),
+            ));
+        }
+        if let Some(max) = self.max_length.filter(|max| length > *max) {
+            errors.push(FieldError::new(
+                field,
+                format!("must be at most {} characters long", max),
+            ));
+        }
+        if self.pattern.as_ref().is_some_and(|p| !p.is_match(value)) {
+            let message = self
+                .pattern_message
+                .as_deref()
+                .unwrap_or("is not in the allowed format");
+            errors.push(FieldError::new(field, message));
+        }
+    }
+}
+
+impl Default for FieldRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implemented by request bodies to declare how their fields are validated.
+pub trait Validate {
+    /// Name of the section in the `validation` config that overrides these
+    /// rules.
+    const NAME: &'static str;
+
+    fn rules() -> Vec<(&'static str, FieldRules)>;
+}
+
+/// Config overrides for every request type, applied on top of the rules the
+/// types declare. Handlers see the ones of the live settings.
+#[derive(Clone, Default)]
+pub struct ValidationRules {
+    overrides: HashMap<String, HashMap<String, FieldRulesOverride>>,
+}
+
+impl ValidationRules {
+    pub fn new(
+        overrides: HashMap<String, HashMap<String, FieldRulesOverride>>,
+    ) -> Result<Self, regex::Error> {
+        // Compile every configured pattern up front so bad config fails at
+        // startup rather than on the first request.
+        for fields in overrides.values() {
+            for rules in fields.values() {
+                if let Some(pattern) = &rules.pattern {
+                    Regex::new(pattern)?;
+                }
+            }
+        }
+
+        Ok(Self { overrides })
+    }
+
+    pub fn rules_for<T: Validate>(&self) -> Vec<(&'static str, FieldRules)> {
+        let overrides = self.overrides.get(T::NAME);
+
+        T::rules()
+            .into_iter()
+            .map(|(field, rules)| {
+                let rules = overrides
+                    .and_then(|fields| fields.get(field))
+                    .and_then(|rules_override| rules.apply(rules_override).ok())
+                    .unwrap_or(rules);
+                (field, rules)
+            })
+            .collect()
+    }
+
+    /// Checks a JSON object against the rules for `T`, trimming fields where
+    /// asked to, and deserializes it.
+    pub fn validate<T>(&self, mut value: Value) -> Result<T, ApiError>
+    where
+        T: DeserializeOwned + Validate,
+    {
+        let Some(object) = value.as_object_mut() else {
+            return Err(ApiError::BadRequest {
+                detail: "Invalid JSON body".to_string(),
+                errors: vec![FieldError::new("body", "expected a JSON object")],
+            });
+        };
+
+        let mut errors = Vec::new();
+        for (field, rules) in &self.rules_for::<T>() {
+            rules.check(field, object.get_mut(*field), &mut errors);
+        }
+
+        if !errors.is_empty() {
+            return Err(ApiError::Validation(errors));
+        }
+
+        serde_json::from_value(value).map_err(|err| ApiError::BadRequest {
+            detail: "Invalid JSON body".to_string(),
+            errors: vec![FieldError::new("body", err.to_string())],
+        })
+    }
+}
+
+/// A JSON body that passed its `Validate` rules. Violations are reported
+/// together as a 422 before the handler runs.
+#[derive(Debug)]
+pub struct Valid<T>(pub T);
+
+impl<'a, T> FromRequest<'a> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+{
+    async fn from_request(req: &'a Request, body: &mut RequestBody) -> poem::Result<Self> {
+        let Json(value) = Json::<Value>::from_request(req, body).await?;
+
+        let validated = match req.data::<LiveSettings>() {
+            Some(live) => live.current().validation.validate::<T>(value),
+            None => ValidationRules::default().validate::<T>(value),
+        };
+
+        validated.map(Valid).map_err(Into::into)
+    }
+}
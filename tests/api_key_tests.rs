@@ -0,0 +1,218 @@
+mod common;
+
+use common::{admin_token, token};
+use playasia::config::Settings;
+use playasia::reload::LiveSettings;
+use playasia::server::{create_app_with, AppState};
+use playasia::tls::PeerIdentities;
+use poem::{http::StatusCode, test::TestClient, Endpoint};
+use serde_json::json;
+use serial_test::serial;
+
+fn app(name: &str) -> impl Endpoint {
+    let path = std::env::temp_dir().join(format!("playasia_{}_api_keys.json", name));
+    let _ = std::fs::remove_file(&path);
+
+    let mut settings = Settings::default();
+    settings.auth.api_keys_file = path.to_string_lossy().into_owned();
+    create_app_with(&settings)
+}
+
+async fn create_key<E: Endpoint>(
+    client: &TestClient<E>,
+    body: serde_json::Value,
+) -> (String, String) {
+    let response = client
+        .post("/admin/api-keys")
+        .body(body.to_string())
+        .header("Authorization", admin_token())
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::CREATED);
+
+    let body = response.json().await;
+    let body = body.value().object();
+    (
+        body.get("id").string().to_string(),
+        body.get("key").string().to_string(),
+    )
+}
+
+#[tokio::test]
+#[serial]
+async fn test_api_key_can_write_items() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app("write_items"));
+    let (_, key) = create_key(&client, json!({"name": "batch", "scopes": ["items:write"]})).await;
+
+    let response = client
+        .post("/items")
+        .body(r#"{"name": "Test Item"}"#)
+        .header("X-API-Key", &key)
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::CREATED);
+
+    let response = client
+        .put("/items/1")
+        .body(r#"{"name": "Zero 2 Prod"}"#)
+        .header("Authorization", format!("ApiKey {}", key))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_api_key_without_scope_is_forbidden() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app("no_scope"));
+    let (_, key) = create_key(&client, json!({"name": "reader"})).await;
+
+    let response = client
+        .post("/items")
+        .body(r#"{"name": "Test Item"}"#)
+        .header("X-API-Key", &key)
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_revoked_and_expired_api_keys_are_rejected() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app("revoked"));
+    let (id, key) = create_key(
+        &client,
+        json!({"name": "partner", "scopes": ["items:write"]}),
+    )
+    .await;
+    let (_, expired) = create_key(
+        &client,
+        json!({"name": "old", "scopes": ["items:write"], "expires_in": 0}),
+    )
+    .await;
+
+    let response = client
+        .delete(format!("/admin/api-keys/{}", id))
+        .header("Authorization", admin_token())
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("revoked")
+        .assert_bool(true);
+
+    for key in [key, expired] {
+        let response = client
+            .post("/items")
+            .body(r#"{"name": "Test Item"}"#)
+            .header("X-API-Key", key)
+            .header("Content-Type", "application/json")
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn test_list_api_keys_hides_secrets() {
+    let client = TestClient::new(app("list"));
+    create_key(&client, json!({"name": "batch", "scopes": ["items:write"]})).await;
+
+    let response = client
+        .get("/admin/api-keys")
+        .header("Authorization", admin_token())
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+
+    let body = response.json().await;
+    let keys = body.value().array();
+    keys.assert_len(1);
+    let key = keys.get(0).object();
+    key.get("name").assert_string("batch");
+    assert!(key.get_opt("key_hash").is_none());
+    assert!(key.get_opt("key").is_none());
+}
+
+#[tokio::test]
+async fn test_admin_endpoints_require_admin_scope() {
+    let client = TestClient::new(app("admin_scope"));
+
+    let response = client.get("/admin/api-keys").send().await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post("/admin/api-keys")
+        .body(r#"{"name": "batch"}"#)
+        .header("Authorization", token(&[]))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_unsaved_api_key_is_not_created() {
+    // The file cannot be written into a directory that does not exist.
+    let path = std::env::temp_dir().join("playasia_missing_dir/api_keys.json");
+    let mut settings = Settings::default();
+    settings.auth.api_keys_file = path.to_string_lossy().into_owned();
+    let client = TestClient::new(create_app_with(&settings));
+
+    let response = client
+        .post("/admin/api-keys")
+        .body(json!({"name": "ci", "scopes": ["items:write"]}).to_string())
+        .header("Authorization", admin_token())
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+
+    let response = client
+        .get("/admin/api-keys")
+        .header("Authorization", admin_token())
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+    response.json().await.value().array().assert_len(0);
+}
+
+#[test]
+fn test_unreadable_keys_stop_startup() {
+    let path = std::env::temp_dir().join("playasia_corrupt_api_keys.json");
+    std::fs::write(&path, "not json").expect("Failed to write API key file.");
+
+    let mut settings = Settings::default();
+    settings.auth.api_keys_file = path.to_string_lossy().into_owned();
+    let Err(err) = AppState::new(LiveSettings::new(settings).unwrap(), PeerIdentities::new())
+    else {
+        panic!("started with an unreadable API key file");
+    };
+    assert!(
+        err.to_string().contains("failed to load API keys from"),
+        "{}",
+        err
+    );
+    assert!(
+        err.to_string().contains("playasia_corrupt_api_keys.json"),
+        "{}",
+        err
+    );
+    // The keys are left for the operator to repair.
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not json");
+}
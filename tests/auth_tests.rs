@@ -0,0 +1,226 @@
+use playasia::auth::issue_token_pair;
+use playasia::config::{AuthSettings, Settings};
+use playasia::server::{create_app, create_app_with};
+use poem::{http::StatusCode, test::TestClient};
+use serde_json::json;
+use serial_test::serial;
+
+fn refresh_body(refresh_token: &str) -> String {
+    json!({ "refresh_token": refresh_token }).to_string()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_refresh_rotates_tokens() {
+    let pair = issue_token_pair(&AuthSettings::default(), "user123", &[], None).unwrap();
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&pair.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+
+    let body = response.json().await;
+    let body = body.value().object();
+    body.get("token_type").assert_string("Bearer");
+    assert_ne!(body.get("refresh_token").string(), pair.refresh_token);
+    assert_ne!(body.get("access_token").string(), pair.access_token);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_refresh_reuse_revokes_family() {
+    let pair = issue_token_pair(&AuthSettings::default(), "user123", &[], None).unwrap();
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    // First use rotates the refresh token.
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&pair.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+    let body = response.json().await;
+    let rotated = body
+        .value()
+        .object()
+        .get("refresh_token")
+        .string()
+        .to_string();
+
+    // Replaying the old refresh token is detected as reuse.
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&pair.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+
+    // The whole family is revoked, including the rotated token.
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&rotated))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_refresh_rejects_access_token() {
+    let pair = issue_token_pair(&AuthSettings::default(), "user123", &[], None).unwrap();
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&pair.access_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_refresh_token_is_not_an_access_token() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let pair = issue_token_pair(&AuthSettings::default(), "user123", &[], None).unwrap();
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client
+        .post("/items")
+        .body(r#"{"name": "Test Item"}"#)
+        .header("Authorization", format!("Bearer {}", pair.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_logout_revokes_tokens() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let pair = issue_token_pair(&AuthSettings::default(), "user123", &[], None).unwrap();
+    let bearer = format!("Bearer {}", pair.access_token);
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client
+        .post("/auth/logout")
+        .body(refresh_body(&pair.refresh_token))
+        .header("Authorization", &bearer)
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+    response
+        .assert_json(json!({ "message": "Logged out successfully" }))
+        .await;
+
+    // The access token is on the denylist before its `exp`.
+    let response = client
+        .post("/items")
+        .body(r#"{"name": "Test Item"}"#)
+        .header("Authorization", &bearer)
+        .header("Content-Type", "application/json")
+        .header("X-Request-Id", "req-revoked")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+    response
+        .assert_json(json!({
+            "type": "urn:playasia:problem:token_revoked",
+            "title": "Unauthorized",
+            "status": 401,
+            "detail": "Token has been revoked",
+            "instance": "/items",
+            "code": "token_revoked",
+            "request_id": "req-revoked"
+        }))
+        .await;
+
+    // The refresh token family can no longer be used.
+    let response = client
+        .post("/auth/refresh")
+        .body(refresh_body(&pair.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_logout_requires_token() {
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client.post("/auth/logout").send().await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_revocations_survive_a_restart() {
+    let path = std::env::temp_dir().join("playasia_revoked_tokens.json");
+    let _ = std::fs::remove_file(&path);
+    let mut settings = Settings::default();
+    settings.auth.revoked_tokens_file = path.to_string_lossy().into_owned();
+
+    let used = issue_token_pair(&settings.auth, "user123", &[], None).unwrap();
+    let logged_out = issue_token_pair(&settings.auth, "user123", &[], None).unwrap();
+    let bearer = format!("Bearer {}", logged_out.access_token);
+
+    let client = TestClient::new(create_app_with(&settings));
+    client
+        .post("/auth/refresh")
+        .body(refresh_body(&used.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    client
+        .post("/auth/logout")
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    // A new server reads back what the old one recorded.
+    let client = TestClient::new(create_app_with(&settings));
+    let response = client
+        .delete("/items/65535")
+        .header("Authorization", &bearer)
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("code")
+        .assert_string("token_revoked");
+    client
+        .post("/auth/refresh")
+        .body(refresh_body(&used.refresh_token))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .assert_status(StatusCode::UNAUTHORIZED);
+}
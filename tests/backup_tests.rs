@@ -0,0 +1,207 @@
+mod common;
+
+use common::{admin_token, get_json, token};
+use playasia::backup::Backups;
+use playasia::config::{DatabaseSettings, Settings};
+use playasia::server::create_app_with;
+use playasia::store::ItemStore;
+use poem::{http::StatusCode, test::TestClient, Endpoint};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Settings for an app whose files live in their own temporary directory,
+/// starting with `items`.
+fn settings(name: &str, items: Value) -> Settings {
+    let dir = std::env::temp_dir().join(format!("playasia_backup_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create test directory.");
+    std::fs::write(dir.join("data.json"), items.to_string()).expect("Failed to write data file.");
+
+    let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+    Settings {
+        database: DatabaseSettings {
+            name: path("data.json"),
+            audit_file: path("audit.jsonl"),
+            revisions_file: path("revisions.jsonl"),
+            backup_dir: path("backups"),
+            ..DatabaseSettings::default()
+        },
+        ..Settings::default()
+    }
+}
+
+async fn post_json<E: Endpoint>(client: &TestClient<E>, path: &str) -> (StatusCode, Value) {
+    let response = client
+        .post(path)
+        .header("Authorization", admin_token())
+        .send()
+        .await;
+    let status = response.0.status();
+    let body = response.0.into_body().into_string().await.unwrap();
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_backup_and_restore() {
+    let settings = settings(
+        "restore",
+        json!([{"id": 1, "name": "Zelda"}, {"id": 2, "name": "Kirby"}]),
+    );
+    let client = TestClient::new(create_app_with(&settings));
+
+    let (status, backup) = post_json(&client, "/admin/backup").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(backup["items"], 2);
+    let name = backup["name"].as_str().unwrap().to_string();
+    let file = PathBuf::from(&settings.database.backup_dir).join(&name);
+    assert_eq!(backup["size"], std::fs::metadata(&file).unwrap().len());
+
+    let backups = get_json(&client, "/admin/backups").await;
+    assert_eq!(backups, json!([backup]));
+
+    client
+        .put("/items/1")
+        .body(r#"{"name": "Zelda II"}"#)
+        .header("Authorization", admin_token())
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    client
+        .delete("/items/2")
+        .header("Authorization", admin_token())
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    let (status, restored) = post_json(&client, &format!("/admin/restore/{}", name)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(restored["name"], name);
+    assert_eq!(restored["items_changed"], 2);
+
+    assert_eq!(
+        get_json(&client, "/items").await,
+        json!([{"id": 1, "name": "Zelda"}, {"id": 2, "name": "Kirby"}])
+    );
+    let restores: Vec<Value> = get_json(&client, "/audit")
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["action"] == "restore")
+        .cloned()
+        .collect();
+    assert_eq!(restores.len(), 2);
+    assert_eq!(restores[0]["before"]["name"], "Zelda II");
+    assert_eq!(restores[0]["after"]["name"], "Zelda");
+    let revisions = get_json(&client, "/items/1/revisions").await;
+    assert_eq!(
+        revisions.as_array().unwrap().last().unwrap()["item"]["name"],
+        "Zelda"
+    );
+}
+
+#[tokio::test]
+async fn test_restore_unknown_backup() {
+    let settings = settings("unknown", json!([]));
+    let client = TestClient::new(create_app_with(&settings));
+
+    for name in ["backup-1.json", "data.json", "..%2Fdata.json"] {
+        let (status, _) = post_json(&client, &format!("/admin/restore/{}", name)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn test_corrupt_backup_is_not_restored() {
+    let settings = settings("corrupt", json!([{"id": 1, "name": "Zelda"}]));
+    let client = TestClient::new(create_app_with(&settings));
+
+    let dir = PathBuf::from(&settings.database.backup_dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("backup-1.json"), "not json").unwrap();
+
+    // Unreadable backups are not listed, and cannot be restored.
+    assert_eq!(get_json(&client, "/admin/backups").await, json!([]));
+    let (status, problem) = post_json(&client, "/admin/restore/backup-1.json").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .contains("cannot be restored"));
+    assert_eq!(
+        get_json(&client, "/items").await,
+        json!([{"id": 1, "name": "Zelda"}])
+    );
+}
+
+#[tokio::test]
+async fn test_backup_with_clashing_items_is_not_restored() {
+    let settings = settings("clashing", json!([{"id": 1, "name": "Zelda"}]));
+    let client = TestClient::new(create_app_with(&settings));
+
+    let dir = PathBuf::from(&settings.database.backup_dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let clashing = json!([{"id": 1, "name": "Kirby"}, {"id": 2, "name": "KIRBY"}]);
+    std::fs::write(dir.join("backup-1.json"), clashing.to_string()).unwrap();
+
+    let (status, problem) = post_json(&client, "/admin/restore/backup-1.json").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(problem["conflicting_id"], 1);
+    assert_eq!(
+        get_json(&client, "/items").await,
+        json!([{"id": 1, "name": "Zelda"}])
+    );
+}
+
+#[tokio::test]
+async fn test_backups_require_admin() {
+    let settings = settings("forbidden", json!([]));
+    let client = TestClient::new(create_app_with(&settings));
+
+    for (method, path) in [
+        ("POST", "/admin/backup"),
+        ("GET", "/admin/backups"),
+        ("POST", "/admin/restore/backup-1.json"),
+    ] {
+        let request = match method {
+            "POST" => client.post(path),
+            _ => client.get(path),
+        };
+        let response = request
+            .header("Authorization", token(&["items:write"]))
+            .send()
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+}
+
+#[test]
+fn test_backup_never_holds_part_of_a_write() {
+    let settings = settings("consistent", json!([]));
+    let store = Arc::new(ItemStore::new(&settings.database));
+    let backups = Backups::new(&settings.database.backup_dir);
+
+    // Items are only ever added ten at a time.
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || {
+            for batch in 0..50 {
+                let names = (0..10).map(|i| format!("Item {}-{}", batch, i)).collect();
+                store.import(names).unwrap();
+            }
+        })
+    };
+
+    let mut taken = Vec::new();
+    while !writer.is_finished() {
+        taken.push(backups.create(&store).unwrap());
+    }
+    writer.join().unwrap();
+    taken.push(backups.create(&store).unwrap());
+
+    assert!(taken.iter().all(|backup| backup.items % 10 == 0));
+    assert_eq!(taken.last().unwrap().items, 500);
+    assert_eq!(backups.list().unwrap().len(), taken.len());
+}
@@ -0,0 +1,219 @@
+use clap::Parser;
+use playasia::auth::{decode_access_token, decode_refresh_token};
+use playasia::cli::{execute, parse_duration, Cli, Command};
+use playasia::schema;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+fn data_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("playasia_cli_{}.json", name));
+    std::fs::write(&path, contents).expect("Failed to write data file.");
+    path
+}
+
+/// Runs the CLI with the given arguments and returns what it printed.
+fn run(args: &[&str]) -> Result<String, String> {
+    let cli = Cli::try_parse_from(std::iter::once("playasia").chain(args.iter().copied()))
+        .map_err(|err| err.to_string())?;
+
+    let mut out = Vec::new();
+    execute(cli, &mut out).map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(out).expect("Output is not UTF-8"))
+}
+
+fn read_json(path: &PathBuf) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(path).expect("Failed to read file."))
+        .expect("File is not JSON")
+}
+
+/// The items of a data file, which must be in the current format.
+fn read_items(path: &PathBuf) -> Value {
+    let data = read_json(path);
+    assert_eq!(data["version"], schema::VERSION, "{}", data);
+    data["items"].clone()
+}
+
+#[test]
+fn test_parse_commands() {
+    let cli = Cli::try_parse_from(["playasia"]).unwrap();
+    assert!(cli.command.is_none());
+
+    let cli = Cli::try_parse_from([
+        "playasia",
+        "serve",
+        "--port",
+        "9000",
+        "--config",
+        "other.yaml",
+        "--data",
+        "items.json",
+    ])
+    .unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Serve { port: Some(9000) })
+    ));
+    assert_eq!(cli.config.config, Some(PathBuf::from("other.yaml")));
+    assert_eq!(cli.config.data, Some(PathBuf::from("items.json")));
+
+    assert!(Cli::try_parse_from(["playasia", "mint-token"]).is_err());
+    assert!(Cli::try_parse_from(["playasia", "--set", "no-value", "check-config"]).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Ok(90));
+    assert_eq!(parse_duration("15m"), Ok(900));
+    assert_eq!(parse_duration("2h"), Ok(7200));
+    assert_eq!(parse_duration("7d"), Ok(604800));
+    assert!(parse_duration("0").is_err());
+    assert!(parse_duration("5w").is_err());
+    assert!(parse_duration("h").is_err());
+    assert_eq!(parse_duration("36500d"), Ok(3153600000));
+    assert!(parse_duration("36501d").is_err());
+    assert!(parse_duration(&u64::MAX.to_string()).is_err());
+}
+
+#[test]
+fn test_check_config() {
+    let output = run(&["check-config", "--set", "application.port=9123"]).unwrap();
+    assert!(output.contains("127.0.0.1:9123"), "{}", output);
+
+    let err = run(&["check-config", "--set", "auth.access_token_ttl=0"]).unwrap_err();
+    assert!(err.contains("`auth.access_token_ttl`"), "{}", err);
+}
+
+#[test]
+fn test_mint_token() {
+    let output = run(&[
+        "mint-token",
+        "--sub",
+        "ops",
+        "--exp",
+        "1h",
+        "--roles",
+        "admin,reports",
+    ])
+    .unwrap();
+
+    let claims = decode_access_token(output.trim(), "secret-key").expect("Invalid token");
+    assert_eq!(claims.sub, "ops");
+    assert_eq!(claims.roles, vec!["admin", "reports"]);
+    assert!(claims.exp as u64 <= playasia::auth::now() + 3600);
+}
+
+#[test]
+fn test_mint_token_pair() {
+    let output = run(&[
+        "mint-token",
+        "--sub",
+        "ops",
+        "--roles",
+        "admin",
+        "--refresh",
+    ])
+    .unwrap();
+    let pair: Value = serde_json::from_str(&output).unwrap();
+
+    let access = decode_access_token(pair["access_token"].as_str().unwrap(), "secret-key")
+        .expect("Invalid access token");
+    let refresh = decode_refresh_token(pair["refresh_token"].as_str().unwrap(), "secret-key")
+        .expect("Invalid refresh token");
+    assert_eq!((access.sub, refresh.sub), ("ops".into(), "ops".into()));
+    assert_eq!(refresh.roles, vec!["admin"]);
+    assert_eq!(pair["token_type"], "Bearer");
+
+    // Both tokens get their configured lifetimes.
+    assert!(run(&["mint-token", "--sub", "ops", "--exp", "1h", "--refresh"]).is_err());
+}
+
+#[test]
+fn test_import_and_export() {
+    let data = data_file("import", r#"[{"id": 1, "name": "Zelda"}]"#);
+    let data_arg = data.to_str().unwrap();
+    let import = data_file(
+        "import_source",
+        r#"[{"id": 1, "name": "  Mario  "}, {"name": "Metroid"}]"#,
+    );
+
+    let output = run(&["import", import.to_str().unwrap(), "--data", data_arg]).unwrap();
+    assert_eq!(output.trim(), "Imported 2 items");
+    assert_eq!(
+        read_items(&data),
+        json!([
+            {"id": 1, "name": "Zelda"},
+            {"id": 2, "name": "Mario"},
+            {"id": 3, "name": "Metroid"},
+        ])
+    );
+
+    let export = std::env::temp_dir().join("playasia_cli_export.json");
+    run(&["export", export.to_str().unwrap(), "--data", data_arg]).unwrap();
+    assert_eq!(read_json(&export), read_items(&data));
+
+    let output = run(&["export", "--data", data_arg]).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&output).unwrap(),
+        read_items(&data)
+    );
+}
+
+#[test]
+fn test_import_is_all_or_nothing() {
+    let data = data_file("import_rejected", r#"[{"id": 1, "name": "Zelda"}]"#);
+    let data_arg = data.to_str().unwrap();
+
+    let invalid = data_file("import_invalid", r#"[{"name": "Mario"}, {"name": ""}]"#);
+    let err = run(&["import", invalid.to_str().unwrap(), "--data", data_arg]).unwrap_err();
+    assert!(err.contains("item 1: name must not be blank"), "{}", err);
+
+    let duplicate = data_file(
+        "import_duplicate",
+        r#"[{"name": "Mario"}, {"name": "ZELDA"}]"#,
+    );
+    let err = run(&["import", duplicate.to_str().unwrap(), "--data", data_arg]).unwrap_err();
+    assert!(err.contains("already exists"), "{}", err);
+
+    assert_eq!(read_items(&data), json!([{"id": 1, "name": "Zelda"}]));
+}
+
+#[test]
+fn test_migrate() {
+    let data = std::env::temp_dir().join("playasia_cli_migrate.json");
+    let _ = std::fs::remove_file(&data);
+
+    let output = run(&["migrate", "--data", data.to_str().unwrap()]).unwrap();
+    assert!(output.contains("0 items"), "{}", output);
+    assert_eq!(read_items(&data), json!([]));
+}
+
+#[test]
+fn test_migrate_dry_run() {
+    let legacy = r#"[{"id": 1, "name": "Zelda"}]"#;
+    let data = data_file("migrate_legacy", legacy);
+    let data_arg = data.to_str().unwrap();
+
+    let output = run(&["migrate", "--dry-run", "--data", data_arg]).unwrap();
+    assert!(output.contains("is at version 1"), "{}", output);
+    assert!(
+        output.contains("version 2: wrap the item array in a versioned envelope"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("version 3: record a checksum of the items\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("Nothing written"), "{}", output);
+    assert_eq!(std::fs::read_to_string(&data).unwrap(), legacy);
+
+    let output = run(&["migrate", "--data", data_arg]).unwrap();
+    let migrated = format!("from version 1 to {}", schema::VERSION);
+    assert!(output.contains(&migrated), "{}", output);
+    assert_eq!(read_items(&data), json!([{"id": 1, "name": "Zelda"}]));
+
+    let output = run(&["migrate", "--dry-run", "--data", data_arg]).unwrap();
+    let up_to_date = format!("is up to date at version {}", schema::VERSION);
+    assert!(output.contains(&up_to_date), "{}", output);
+}
@@ -0,0 +1,41 @@
+//! Fixtures shared by the integration tests. Each test crate uses only some
+//! of them.
+#![allow(dead_code)]
+
+use playasia::auth::issue_token_pair;
+use playasia::config::AuthSettings;
+use poem::{http::StatusCode, test::TestClient, Endpoint};
+use serde_json::Value;
+
+/// A Bearer token for the subject `admin` with `roles`, signed with the
+/// default secret.
+pub fn token(roles: &[&str]) -> String {
+    let roles: Vec<String> = roles.iter().map(|role| role.to_string()).collect();
+    let pair = issue_token_pair(&AuthSettings::default(), "admin", &roles, None).unwrap();
+    format!("Bearer {}", pair.access_token)
+}
+
+pub fn admin_token() -> String {
+    token(&["admin"])
+}
+
+/// Fetches `path` as an admin, expecting `200 OK`, and returns the JSON body.
+pub async fn get_json<E: Endpoint>(client: &TestClient<E>, path: &str) -> Value {
+    get_json_as(client, path, &admin_token()).await
+}
+
+/// Fetches `path` with the `Authorization` header `authorization`, expecting
+/// `200 OK`, and returns the JSON body.
+pub async fn get_json_as<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    authorization: &str,
+) -> Value {
+    let response = client
+        .get(path)
+        .header("Authorization", authorization)
+        .send()
+        .await;
+    response.assert_status(StatusCode::OK);
+    serde_json::from_str(&response.0.into_body().into_string().await.unwrap()).unwrap()
+}
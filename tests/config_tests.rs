@@ -0,0 +1,182 @@
+use playasia::config::{ConfigError, ConfigLoader};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const BASE: &str = r#"
+application:
+  port: 8000
+  address: 127.0.0.1
+database:
+  name: "data.json"
+auth:
+  secret: "base-secret"
+"#;
+
+/// Writes the given files into a fresh directory and returns the path of
+/// its `config.yaml`.
+fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("playasia_config_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create config dir.");
+
+    for (file, contents) in files {
+        std::fs::write(dir.join(file), contents).expect("Failed to write config file.");
+    }
+
+    dir.join("config.yaml")
+}
+
+fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_base_file_only() {
+    let path = config_dir("base", &[("config.yaml", BASE)]);
+
+    let settings = ConfigLoader::new()
+        .path(&path)
+        .env(env(&[]))
+        .load()
+        .expect("Failed to load config");
+
+    assert_eq!(settings.application.port, 8000);
+    assert_eq!(settings.auth.secret, "base-secret");
+    assert_eq!(settings.database.unique_fields, vec!["name"]);
+}
+
+#[test]
+fn test_layers_are_applied_in_order() {
+    let path = config_dir(
+        "layers",
+        &[
+            ("config.yaml", BASE),
+            (
+                "config.prod.yaml",
+                "application:\n  port: 9000\n  address: 0.0.0.0\nauth:\n  secret: prod-secret\n",
+            ),
+        ],
+    );
+
+    let settings = ConfigLoader::new()
+        .path(&path)
+        .profile("prod")
+        .env(env(&[
+            ("PLAYASIA__APPLICATION__PORT", "9100"),
+            ("PLAYASIA__AUTH__ACCESS_TOKEN_TTL", "60"),
+            ("PLAYASIA__TLS__CLIENT_SCOPES", "items:write,admin"),
+            ("UNRELATED__APPLICATION__PORT", "1"),
+        ]))
+        .set("application.port", "9200")
+        .load()
+        .expect("Failed to load config");
+
+    // The profile overrides the base file ...
+    assert_eq!(settings.application.address, "0.0.0.0");
+    assert_eq!(settings.auth.secret, "prod-secret");
+    // ... the environment overrides the profile ...
+    assert_eq!(settings.auth.access_token_ttl, 60);
+    assert_eq!(settings.tls.client_scopes, vec!["items:write", "admin"]);
+    // ... and explicit overrides win over everything.
+    assert_eq!(settings.application.port, 9200);
+}
+
+#[test]
+fn test_unknown_or_missing_profile() {
+    let path = config_dir("profiles", &[("config.yaml", BASE)]);
+
+    let err = ConfigLoader::new()
+        .path(&path)
+        .profile("staging")
+        .env(env(&[]))
+        .load()
+        .err()
+        .expect("Unknown profile was accepted");
+    assert_eq!(err.keys(), vec!["profile"]);
+
+    let err = ConfigLoader::new()
+        .path(&path)
+        .profile("dev")
+        .env(env(&[]))
+        .load()
+        .err()
+        .expect("Missing profile file was accepted");
+    assert!(err.to_string().contains("config.dev.yaml"), "{}", err);
+}
+
+#[test]
+fn test_type_errors_name_the_key() {
+    let path = config_dir("types", &[("config.yaml", BASE)]);
+
+    let err = ConfigLoader::new()
+        .path(&path)
+        .env(env(&[("PLAYASIA__APPLICATION__PORT", "eighty")]))
+        .load()
+        .err()
+        .expect("Invalid port was accepted");
+
+    assert!(matches!(err, ConfigError::Source(_)));
+    assert!(err.to_string().contains("`application.port`"), "{}", err);
+}
+
+#[test]
+fn test_invalid_values_are_reported_by_key() {
+    let path = config_dir(
+        "invalid",
+        &[(
+            "config.yaml",
+            r#"
+application:
+  port: 8000
+  address: ""
+database:
+  name: "data.json"
+auth:
+  access_token_ttl: 0
+  signing_keys:
+    - id: ci
+      secret: one
+    - id: ci
+      secret: two
+tls:
+  require_client_cert: true
+validation:
+  item:
+    name:
+      min_length: 10
+      max_length: 5
+      pattern: "(unclosed"
+"#,
+        )],
+    );
+
+    let err = ConfigLoader::new()
+        .path(&path)
+        .env(env(&[]))
+        .load()
+        .err()
+        .expect("Invalid config was accepted");
+
+    assert_eq!(
+        err.keys(),
+        vec![
+            "application.address",
+            "auth.access_token_ttl",
+            "auth.signing_keys[1].id",
+            "tls.client_ca",
+            "validation.item.name.min_length",
+            "validation.item.name.pattern",
+        ]
+    );
+}
+
+#[test]
+fn test_repository_config_is_valid() {
+    ConfigLoader::new()
+        .path("config.yaml")
+        .env(env(&[]))
+        .load()
+        .expect("config.yaml is invalid");
+}
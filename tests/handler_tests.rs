@@ -1,4 +1,5 @@
-use playasia::server::create_app;
+use playasia::config::Settings;
+use playasia::server::{create_app, create_app_with};
 use poem::{http::StatusCode, test::TestClient};
 use serde_json::json;
 use serial_test::serial;
@@ -195,3 +196,54 @@ async fn test_delete_item() {
         }))
         .await;
 }
+
+#[tokio::test]
+#[serial]
+async fn test_get_missing_item() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let app = create_app();
+    let client = TestClient::new(app);
+
+    let response = client
+        .get("/items/42")
+        .header("X-Request-Id", "req-missing-item")
+        .send()
+        .await;
+    response.assert_status(StatusCode::NOT_FOUND);
+    response.assert_content_type("application/problem+json");
+    response
+        .assert_json(json!({
+            "type": "urn:playasia:problem:not_found",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "Item not found",
+            "instance": "/items/42",
+            "code": "not_found",
+            "request_id": "req-missing-item"
+        }))
+        .await;
+}
+
+#[tokio::test]
+#[serial]
+async fn test_storage_error() {
+    std::fs::write("data.json", "not json").expect("Failed to reset data file.");
+
+    // Keep the broken file rather than recovering from it on startup.
+    let mut settings = Settings::default();
+    settings.database.recover = false;
+    let app = create_app_with(&settings);
+    let client = TestClient::new(app);
+
+    let response = client.get("/items").send().await;
+    response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    response.assert_content_type("application/problem+json");
+
+    let body = response.json().await;
+    let body = body.value().object();
+    body.get("code").assert_string("storage_error");
+    body.get("instance").assert_string("/items");
+
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+}
@@ -0,0 +1,229 @@
+use playasia::config::{ConfigLoader, Settings};
+use playasia::reload::{LiveSettings, Reloader};
+use playasia::routes::Item;
+use playasia::schema;
+use playasia::server::{create_app, create_app_with, create_app_with_live};
+use playasia::tls::PeerIdentities;
+use poem::{http::StatusCode, test::TestClient, Endpoint};
+use serde_json::Value;
+use serial_test::serial;
+use std::collections::HashMap;
+
+/// Fetches the readiness report, returning its status code and the status
+/// and detail of each check by name.
+async fn readiness<E: Endpoint>(app: E) -> (StatusCode, HashMap<String, (String, String)>) {
+    let client = TestClient::new(app);
+    let response = client.get("/health/ready").send().await;
+    let status = response.0.status();
+
+    let body: Value = response.0.into_body().into_json().await.unwrap();
+    let checks = body["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| {
+            assert!(check["latency_ms"].is_f64(), "{}", check);
+            (
+                check["name"].as_str().unwrap().to_string(),
+                (
+                    check["status"].as_str().unwrap().to_string(),
+                    check["detail"].as_str().unwrap_or_default().to_string(),
+                ),
+            )
+        })
+        .collect();
+
+    (status, checks)
+}
+
+#[tokio::test]
+async fn test_liveness() {
+    let client = TestClient::new(create_app());
+
+    let response = client.get("/health/live").send().await;
+    response.assert_status(StatusCode::OK);
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("status")
+        .assert_string("pass");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ready() {
+    std::fs::write("data.json", r#"[{"id": 1, "name": "Zelda"}]"#)
+        .expect("Failed to reset data file.");
+
+    let (status, checks) = readiness(create_app()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(checks["storage"], ("pass".into(), "1 items".into()));
+    assert_eq!(checks["config"].0, "pass");
+    assert_eq!(checks["keys"].0, "pass");
+
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_not_ready_when_storage_is_broken() {
+    // Without recovery, a broken data file is reported as it is.
+    let mut settings = Settings::default();
+    settings.database.recover = false;
+
+    for (data, detail) in [
+        ("not json", "expected"),
+        (
+            r#"[{"id": 1, "name": "Zelda"}, {"id": 1, "name": "Mario"}]"#,
+            "duplicate item id 1",
+        ),
+    ] {
+        std::fs::write("data.json", data).expect("Failed to write data file.");
+
+        let (status, checks) = readiness(create_app_with(&settings)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(checks["storage"].0, "fail");
+        assert!(checks["storage"].1.contains(detail), "{:?}", checks);
+    }
+
+    settings.database.name = std::env::temp_dir()
+        .join("playasia_health_missing.json")
+        .to_string_lossy()
+        .into_owned();
+    let (status, checks) = readiness(create_app_with(&settings)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(checks["storage"].0, "fail");
+
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+}
+
+#[tokio::test]
+async fn test_recovery_is_a_warning() {
+    let dir = std::env::temp_dir().join("playasia_health_recovery");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let item = Item::new(1, "Zelda".to_string());
+    std::fs::write(
+        dir.join("data.json.bak.1"),
+        schema::encode(&[&item], 1).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("data.json"), "not json").unwrap();
+
+    let mut settings = Settings::default();
+    settings.database.name = dir.join("data.json").to_string_lossy().into_owned();
+    settings.database.revisions_file = dir.join("revisions.jsonl").to_string_lossy().into_owned();
+
+    let (status, checks) = readiness(create_app_with(&settings)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(checks["storage"].0, "warn");
+    assert!(checks["storage"].1.starts_with("1 items; "), "{:?}", checks);
+    assert!(
+        checks["storage"].1.contains("restored from"),
+        "{:?}",
+        checks
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_not_ready_without_tls_material() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let mut settings = Settings::default();
+    settings.tls.enabled = true;
+    settings.tls.cert = "/nonexistent/server.pem".to_string();
+
+    let (status, checks) = readiness(create_app_with(&settings)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(checks["keys"].0, "fail");
+    assert!(checks["keys"].1.contains("/nonexistent/server.pem"));
+    assert_eq!(checks["storage"].0, "pass");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_rejected_reload_is_a_warning() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let dir = std::env::temp_dir().join("playasia_health_reload");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.yaml");
+    std::fs::write(
+        &path,
+        "application:\n  port: 8000\n  address: \"\"\ndatabase:\n  name: data.json\n",
+    )
+    .unwrap();
+
+    let live = LiveSettings::new(Settings::default()).unwrap();
+    let reloader = Reloader::new(
+        ConfigLoader::new().path(&path).env(HashMap::new()),
+        live.clone(),
+    );
+    assert!(reloader.reload().is_err());
+
+    let (status, checks) = readiness(create_app_with_live(live, PeerIdentities::new())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(checks["config"].0, "warn");
+    assert!(
+        checks["config"].1.contains("application.address"),
+        "{:?}",
+        checks
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_health_is_responsive_during_slow_storage() {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let dir = std::env::temp_dir().join("playasia_health_slow");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("data.json");
+    std::fs::write(&data, "[]").unwrap();
+
+    let mut settings = Settings::default();
+    settings.database.name = data.to_string_lossy().into_owned();
+    settings.database.refresh_interval_ms = 0;
+    let app = Arc::new(create_app_with(&settings));
+
+    // Reading from a FIFO blocks until something is written to it, which
+    // stands in for a slow disk.
+    std::fs::remove_file(&data).unwrap();
+    let status = std::process::Command::new("mkfifo")
+        .arg(&data)
+        .status()
+        .expect("Failed to run mkfifo.");
+    assert!(status.success());
+
+    // Release the read after a while, whether or not the runtime is stuck.
+    let writer = {
+        let data = data.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(1));
+            let item = Item::new(1, "Zelda".to_string());
+            std::fs::write(data, schema::encode(&[&item], 1).unwrap()).unwrap();
+        })
+    };
+
+    let slow = TestClient::new(app.clone());
+    let items = tokio::spawn(async move { slow.get("/items").send().await.0.status() });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let started = Instant::now();
+    let response = TestClient::new(app).get("/health").send().await;
+    response.assert_status(StatusCode::OK);
+    assert!(
+        started.elapsed() < Duration::from_millis(500),
+        "/health took {:?}",
+        started.elapsed()
+    );
+    assert!(!items.is_finished());
+
+    assert_eq!(items.await.unwrap(), StatusCode::OK);
+    writer.join().unwrap();
+}
@@ -226,7 +226,7 @@ mod token_tests {
                 .header("Content-Type", "application/json")
                 .send()
                 .await;
-            response.assert_status(StatusCode::BAD_REQUEST);
+            response.assert_status(StatusCode::UNAUTHORIZED);
         }
 
         #[tokio::test]
@@ -257,7 +257,7 @@ mod token_tests {
                 .header("Content-Type", "application/json")
                 .send()
                 .await;
-            response.assert_status(StatusCode::BAD_REQUEST);
+            response.assert_status(StatusCode::UNAUTHORIZED);
         }
 
         #[tokio::test]
@@ -288,7 +288,140 @@ mod token_tests {
                 .header("Content-Type", "application/json")
                 .send()
                 .await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    mod with_malformed_header {
+        use crate::token_tests::VALID_TOKEN;
+        use playasia::server::create_app;
+        use poem::{http::StatusCode, test::TestClient};
+        use serial_test::serial;
+
+        async fn post_with_authorization(authorization: &str) -> poem::test::TestResponse {
+            let app = create_app();
+            let client = TestClient::new(app);
+
+            client
+                .post("/items")
+                .body(r#"{"name": "Test Item"}"#)
+                .header("Authorization", authorization)
+                .header("Content-Type", "application/json")
+                .send()
+                .await
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_scheme_without_token() {
+            for header in ["Bearer", "Bearer ", "bearer   ", "ApiKey"] {
+                let response = post_with_authorization(header).await;
+                response.assert_status(StatusCode::BAD_REQUEST);
+                response.assert_header(
+                    "WWW-Authenticate",
+                    r#"Bearer realm="playasia", error="invalid_request", error_description="Malformed authorization header""#,
+                );
+            }
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_token_with_embedded_whitespace() {
+            let token = &VALID_TOKEN[7..];
+            let header = format!("Bearer {} {}", &token[..10], &token[10..]);
+
+            let response = post_with_authorization(&header).await;
+            response.assert_status(StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_scheme_glued_to_token() {
+            let header = format!("Bearer{}", &VALID_TOKEN[7..]);
+
+            let response = post_with_authorization(&header).await;
             response.assert_status(StatusCode::BAD_REQUEST);
+
+            let header = format!("BearerX {}", &VALID_TOKEN[7..]);
+
+            let response = post_with_authorization(&header).await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header("WWW-Authenticate", r#"Bearer realm="playasia""#);
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_unsupported_scheme() {
+            let response = post_with_authorization("Basic dXNlcjpwYXNz").await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header("WWW-Authenticate", r#"Bearer realm="playasia""#);
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_scheme_is_case_insensitive() {
+            let token = &VALID_TOKEN[7..];
+            for header in [
+                format!("bearer {}", token),
+                format!("BEARER {}", token),
+                format!("  Bearer    {}  ", token),
+                format!("Bearer\t{}", token),
+            ] {
+                std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+                let response = post_with_authorization(&header).await;
+                response.assert_status(StatusCode::CREATED);
+            }
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_missing_header_challenge() {
+            let app = create_app();
+            let client = TestClient::new(app);
+
+            let response = client
+                .post("/items")
+                .body(r#"{"name": "Test Item"}"#)
+                .header("Content-Type", "application/json")
+                .send()
+                .await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header("WWW-Authenticate", r#"Bearer realm="playasia""#);
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_invalid_and_expired_token_challenge() {
+            use jsonwebtoken::{encode, EncodingKey, Header};
+            use playasia::auth::{now, Claims};
+
+            let expired = encode(
+                &Header::default(),
+                &Claims {
+                    sub: "user123".to_string(),
+                    exp: (now() - 3600) as usize,
+                    jti: None,
+                    typ: None,
+                    roles: Vec::new(),
+                },
+                &EncodingKey::from_secret(b"secret-key"),
+            )
+            .unwrap();
+
+            let response = post_with_authorization(&format!("Bearer {}", expired)).await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header(
+                "WWW-Authenticate",
+                r#"Bearer realm="playasia", error="invalid_token", error_description="Token has expired""#,
+            );
+
+            let response = post_with_authorization("Bearer not-a-jwt").await;
+            response.assert_status(StatusCode::UNAUTHORIZED);
+            response.assert_header(
+                "WWW-Authenticate",
+                r#"Bearer realm="playasia", error="invalid_token", error_description="Invalid token""#,
+            );
         }
     }
 }
@@ -0,0 +1,163 @@
+use playasia::auth::{now, SignedRequest};
+use playasia::config::{Settings, SigningKey};
+use playasia::server::create_app_with;
+use poem::{http::StatusCode, test::TestClient, Endpoint};
+use serial_test::serial;
+
+const KEY_ID: &str = "billing";
+const SECRET: &str = "shared-secret";
+
+fn app() -> impl Endpoint {
+    let mut settings = Settings::default();
+    settings.auth.signing_keys = vec![SigningKey {
+        id: KEY_ID.to_string(),
+        secret: SECRET.to_string(),
+        scopes: vec!["items:write".to_string()],
+    }];
+    create_app_with(&settings)
+}
+
+fn sign(method: &str, path: &str, timestamp: u64, nonce: &str, body: &str) -> String {
+    SignedRequest {
+        method,
+        path,
+        timestamp,
+        nonce,
+        body: body.as_bytes(),
+    }
+    .sign(SECRET)
+}
+
+#[tokio::test]
+#[serial]
+async fn test_signed_request_is_accepted() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app());
+    let body = r#"{"name": "Test Item"}"#;
+    let timestamp = now();
+
+    let response = client
+        .post("/items")
+        .body(body)
+        .header("Content-Type", "application/json")
+        .header("X-Signature-Key-Id", KEY_ID)
+        .header("X-Signature-Timestamp", timestamp)
+        .header("X-Signature-Nonce", "nonce-1")
+        .header(
+            "X-Signature",
+            sign("POST", "/items", timestamp, "nonce-1", body),
+        )
+        .send()
+        .await;
+    response.assert_status(StatusCode::CREATED);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_tampered_body_is_rejected() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app());
+    let timestamp = now();
+    let signature = sign(
+        "POST",
+        "/items",
+        timestamp,
+        "nonce-1",
+        r#"{"name": "Test Item"}"#,
+    );
+
+    let response = client
+        .post("/items")
+        .body(r#"{"name": "Tampered"}"#)
+        .header("Content-Type", "application/json")
+        .header("X-Signature-Key-Id", KEY_ID)
+        .header("X-Signature-Timestamp", timestamp)
+        .header("X-Signature-Nonce", "nonce-1")
+        .header("X-Signature", signature)
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replayed_request_is_rejected() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app());
+    let body = r#"{"name": "Test Item"}"#;
+    let timestamp = now();
+    let signature = sign("POST", "/items", timestamp, "nonce-1", body);
+
+    for expected in [StatusCode::CREATED, StatusCode::UNAUTHORIZED] {
+        let response = client
+            .post("/items")
+            .body(body)
+            .header("Content-Type", "application/json")
+            .header("X-Signature-Key-Id", KEY_ID)
+            .header("X-Signature-Timestamp", timestamp)
+            .header("X-Signature-Nonce", "nonce-1")
+            .header("X-Signature", &signature)
+            .send()
+            .await;
+        response.assert_status(expected);
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_stale_timestamp_is_rejected() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let client = TestClient::new(app());
+    let body = r#"{"name": "Test Item"}"#;
+    let timestamp = now() - 3600;
+
+    let response = client
+        .post("/items")
+        .body(body)
+        .header("Content-Type", "application/json")
+        .header("X-Signature-Key-Id", KEY_ID)
+        .header("X-Signature-Timestamp", timestamp)
+        .header("X-Signature-Nonce", "nonce-1")
+        .header(
+            "X-Signature",
+            sign("POST", "/items", timestamp, "nonce-1", body),
+        )
+        .header("X-Request-Id", "req-stale")
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+    response
+        .assert_json(serde_json::json!({
+            "type": "urn:playasia:problem:signature_expired",
+            "title": "Unauthorized",
+            "status": 401,
+            "detail": "Request signature has expired",
+            "instance": "/items",
+            "code": "signature_expired",
+            "request_id": "req-stale"
+        }))
+        .await;
+}
+
+#[tokio::test]
+async fn test_unknown_key_is_rejected() {
+    let client = TestClient::new(app());
+    let timestamp = now();
+
+    let response = client
+        .delete("/items/1")
+        .header("X-Signature-Key-Id", "unknown")
+        .header("X-Signature-Timestamp", timestamp)
+        .header("X-Signature-Nonce", "nonce-1")
+        .header(
+            "X-Signature",
+            sign("DELETE", "/items/1", timestamp, "nonce-1", ""),
+        )
+        .send()
+        .await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
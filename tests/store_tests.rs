@@ -0,0 +1,619 @@
+use playasia::config::DatabaseSettings;
+use playasia::routes::Item;
+use playasia::schema;
+use playasia::store::{ItemStore, Recovery, StoreError};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// Settings for a store in its own temporary directory, holding `items`.
+fn settings(name: &str, items: Value, compact_after: usize) -> DatabaseSettings {
+    let dir = std::env::temp_dir().join(format!("playasia_store_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create store directory.");
+    std::fs::write(dir.join("data.json"), items.to_string()).expect("Failed to write data file.");
+
+    DatabaseSettings {
+        name: dir.join("data.json").to_string_lossy().into_owned(),
+        revisions_file: dir.join("revisions.jsonl").to_string_lossy().into_owned(),
+        compact_after,
+        ..DatabaseSettings::default()
+    }
+}
+
+fn log_path(settings: &DatabaseSettings) -> PathBuf {
+    PathBuf::from(format!("{}.log", settings.name))
+}
+
+fn read_json(settings: &DatabaseSettings) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(&settings.name).unwrap()).unwrap()
+}
+
+/// The items in the data file.
+fn snapshot(settings: &DatabaseSettings) -> Value {
+    read_json(settings)["items"].clone()
+}
+
+fn names(store: &ItemStore) -> Vec<String> {
+    store
+        .all()
+        .unwrap()
+        .into_iter()
+        .map(|item| item.name)
+        .collect()
+}
+
+#[test]
+fn test_changes_are_replayed_from_the_log() {
+    let settings = settings("replay", json!([{"id": 1, "name": "Zelda"}]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Kirby".to_string()).unwrap();
+    store.update(1, "Zelda II".to_string()).unwrap();
+    store.create("Metroid".to_string()).unwrap();
+    store.delete(3).unwrap();
+
+    // Nothing was compacted, so the snapshot is unchanged.
+    assert_eq!(snapshot(&settings), json!([{"id": 1, "name": "Zelda"}]));
+
+    let reopened = ItemStore::new(&settings);
+    assert_eq!(names(&reopened), ["Zelda II", "Kirby"]);
+    assert_eq!(reopened.trash().unwrap()[0].name, "Metroid");
+
+    // Trashed items keep their ID, and deletion released the name.
+    assert_eq!(reopened.create("Metroid".to_string()).unwrap().id, 4);
+}
+
+#[test]
+fn test_log_is_compacted() {
+    let settings = settings("compact", json!([]), 3);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    assert_eq!(snapshot(&settings), json!([]));
+
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(
+        snapshot(&settings),
+        json!([
+            {"id": 1, "name": "Zelda"},
+            {"id": 2, "name": "Kirby"},
+            {"id": 3, "name": "Metroid"}
+        ])
+    );
+    assert_eq!(
+        std::fs::read_to_string(log_path(&settings))
+            .unwrap()
+            .lines()
+            .count(),
+        1
+    );
+
+    store.update(1, "Zelda II".to_string()).unwrap();
+    store.flush().unwrap();
+    assert_eq!(snapshot(&settings)[0], json!({"id": 1, "name": "Zelda II"}));
+    assert_eq!(
+        names(&ItemStore::new(&settings)),
+        ["Zelda II", "Kirby", "Metroid"]
+    );
+}
+
+#[test]
+fn test_torn_write_is_discarded() {
+    let settings = settings("torn", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    drop(store);
+
+    // A crash in the middle of an append leaves a line without its newline.
+    let mut log = std::fs::read(log_path(&settings)).unwrap();
+    let complete = log.len();
+    log.extend_from_slice(br#"{"op":"put","item":{"id":2,"na"#);
+    std::fs::write(log_path(&settings), log).unwrap();
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(names(&store), ["Zelda"]);
+    assert_eq!(
+        std::fs::metadata(log_path(&settings)).unwrap().len(),
+        complete as u64
+    );
+
+    store.create("Kirby".to_string()).unwrap();
+    assert_eq!(names(&ItemStore::new(&settings)), ["Zelda", "Kirby"]);
+}
+
+#[test]
+fn test_log_of_another_snapshot_is_rejected() {
+    let settings = settings("stale", json!([]), 100);
+
+    ItemStore::new(&settings)
+        .create("Zelda".to_string())
+        .unwrap();
+    std::fs::write(&settings.name, r#"[{"id": 1, "name": "Kirby"}]"#).unwrap();
+
+    // The logged change is not in the new data file, so it would be lost.
+    let store = ItemStore::new(&settings);
+    let err = store.check().unwrap_err();
+    assert!(matches!(err, StoreError::Corrupt(_)), "{}", err);
+    assert!(err.to_string().contains("1 changes"), "{}", err);
+
+    let recovery = store.recover().unwrap().unwrap();
+    assert!(
+        matches!(recovery, Recovery::LogQuarantined { .. }),
+        "{}",
+        recovery
+    );
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(names(&ItemStore::new(&settings)), ["Kirby", "Metroid"]);
+}
+
+#[test]
+fn test_log_survives_touching_the_data_file() {
+    let settings = settings("touched", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    drop(store);
+
+    let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+    std::fs::File::options()
+        .write(true)
+        .open(&settings.name)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(names(&store), ["Zelda", "Kirby"]);
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(
+        names(&ItemStore::new(&settings)),
+        ["Zelda", "Kirby", "Metroid"]
+    );
+}
+
+#[test]
+fn test_log_left_by_an_interrupted_compaction_is_ignored() {
+    let settings = settings("interrupted", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    store.delete(1).unwrap();
+    let items = store.all().unwrap();
+    let trash = store.trash().unwrap();
+    drop(store);
+
+    // The snapshot was rewritten, but the crash came before the log was.
+    let mut all: Vec<&Item> = items.iter().chain(&trash).collect();
+    all.sort_by_key(|item| item.id);
+    std::fs::write(&settings.name, schema::encode(&all, 2).unwrap()).unwrap();
+
+    let store = ItemStore::new(&settings);
+    store.check().unwrap();
+    assert_eq!(names(&store), ["Kirby"]);
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(names(&ItemStore::new(&settings)), ["Kirby", "Metroid"]);
+}
+
+#[test]
+fn test_corrupt_log() {
+    let settings = settings("corrupt", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    drop(store);
+
+    let log = std::fs::read_to_string(log_path(&settings)).unwrap();
+    let mut lines: Vec<&str> = log.lines().collect();
+    lines[1] = "not json";
+    std::fs::write(log_path(&settings), lines.join("\n") + "\n").unwrap();
+
+    let err = ItemStore::new(&settings).check().unwrap_err();
+    assert!(matches!(err, StoreError::Corrupt(_)), "{}", err);
+    assert!(err.to_string().contains("line 2"), "{}", err);
+}
+
+#[test]
+fn test_reads_follow_external_changes() {
+    let mut settings = settings("external", json!([{"id": 1, "name": "Zelda"}]), 100);
+    settings.refresh_interval_ms = 0;
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(names(&store), ["Zelda"]);
+
+    // Make sure the rewritten file gets a different modification time.
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    std::fs::write(&settings.name, r#"[{"id": 1, "name": "Kirby"}]"#).unwrap();
+    assert_eq!(names(&store), ["Kirby"]);
+    assert_eq!(store.get(1).unwrap().name, "Kirby");
+}
+
+#[test]
+fn test_reads_are_cached() {
+    let mut settings = settings("cached", json!([{"id": 1, "name": "Zelda"}]), 100);
+    settings.refresh_interval_ms = 60_000;
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(names(&store), ["Zelda"]);
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    std::fs::write(&settings.name, r#"[{"id": 1, "name": "Kirby"}]"#).unwrap();
+    assert_eq!(names(&store), ["Zelda"]);
+
+    // Writes always look at the files, and are visible to reads at once.
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(names(&store), ["Kirby", "Metroid"]);
+}
+
+#[test]
+fn test_restore_item_without_history() {
+    let settings = settings("baseline", json!([{"id": 1, "name": "Zelda"}]), 100);
+
+    // The only revision is the item as it is, which restores to itself.
+    let store = ItemStore::new(&settings);
+    let (previous, item) = store.restore(1, 1).unwrap();
+    assert_eq!(previous.unwrap().name, "Zelda");
+    assert_eq!(item.name, "Zelda");
+    assert_eq!(store.revisions(1).unwrap().len(), 2);
+}
+
+#[test]
+fn test_legacy_data_file_is_upgraded() {
+    let settings = settings("legacy", json!([{"id": 1, "name": "Zelda"}]), 100);
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(names(&store), ["Zelda"]);
+    assert_eq!(read_json(&settings)["version"], schema::VERSION);
+    assert_eq!(snapshot(&settings), json!([{"id": 1, "name": "Zelda"}]));
+}
+
+#[test]
+fn test_each_older_version_is_checked_and_upgraded() {
+    let version_2 = settings(
+        "version_2",
+        json!({"version": 2, "items": [{"id": 1, "name": "Zelda"}]}),
+        100,
+    );
+    let store = ItemStore::new(&version_2);
+    let plan = store.plan_migration().unwrap();
+    let upgraded: Vec<u32> = plan.steps.iter().map(|step| step.version).collect();
+    assert_eq!(upgraded, (3..=schema::VERSION).collect::<Vec<_>>());
+
+    store.migrate().unwrap();
+    let data = read_json(&version_2);
+    assert_eq!(data["version"], schema::VERSION);
+    assert!(data["checksum"].is_string(), "{}", data);
+
+    // A version 3 file is held to the checksum of its own version.
+    let version_3 = settings(
+        "version_3",
+        json!({"version": 3, "checksum": "0", "items": []}),
+        100,
+    );
+    let err = ItemStore::new(&version_3).check().unwrap_err();
+    assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+}
+
+#[test]
+fn test_newer_data_file_is_rejected() {
+    let settings = settings(
+        "newer",
+        json!({"version": schema::VERSION + 1, "items": []}),
+        100,
+    );
+
+    let err = ItemStore::new(&settings).check().unwrap_err();
+    assert!(matches!(err, StoreError::UnsupportedVersion(_)), "{}", err);
+}
+
+#[test]
+fn test_checksum_mismatch_is_corrupt() {
+    let settings = settings("checksum", json!([{"id": 1, "name": "Zelda"}]), 100);
+    ItemStore::new(&settings).check().unwrap();
+
+    // Editing an item without updating the checksum is caught on load.
+    let data = std::fs::read_to_string(&settings.name).unwrap();
+    std::fs::write(&settings.name, data.replace("Zelda", "Kirby")).unwrap();
+
+    let err = ItemStore::new(&settings).check().unwrap_err();
+    assert!(matches!(err, StoreError::Corrupt(_)), "{}", err);
+    assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+}
+
+#[test]
+fn test_backups_are_rotated() {
+    let mut settings = settings("backups", json!([]), 1);
+    settings.backups = 2;
+
+    let store = ItemStore::new(&settings);
+    for name in ["Zelda", "Kirby", "Metroid", "Mario"] {
+        store.create(name.to_string()).unwrap();
+    }
+
+    let backup = |number: usize| format!("{}.bak.{}", settings.name, number);
+    let backed_up = |number: usize| -> Vec<String> {
+        let data: Value =
+            serde_json::from_str(&std::fs::read_to_string(backup(number)).unwrap()).unwrap();
+        data["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Each compaction backs up the snapshot it replaces, with the log of
+    // the change made to it.
+    assert_eq!(backed_up(1), ["Zelda", "Kirby", "Metroid"]);
+    assert_eq!(backed_up(2), ["Zelda", "Kirby"]);
+    assert!(!std::path::Path::new(&backup(3)).exists());
+    let logged = |number: usize| std::fs::read_to_string(format!("{}.log", backup(number)));
+    assert!(logged(1).unwrap().contains("Mario"));
+    assert!(logged(2).unwrap().contains("Metroid"));
+    assert!(logged(3).is_err());
+}
+
+#[test]
+fn test_corrupt_data_file_is_restored_from_a_backup() {
+    let mut settings = settings("restore", json!([]), 2);
+    settings.backups = 3;
+
+    let store = ItemStore::new(&settings);
+    for name in ["Zelda", "Kirby", "Metroid", "Mario", "Luigi"] {
+        store.create(name.to_string()).unwrap();
+    }
+    drop(store);
+
+    std::fs::write(&settings.name, "not json").unwrap();
+    // The newest backup is damaged too, so the one before it is used.
+    std::fs::write(format!("{}.bak.1", settings.name), "{").unwrap();
+
+    let store = ItemStore::new(&settings);
+    let recovery = store.recover().unwrap().unwrap();
+    let Recovery::Restored {
+        quarantined,
+        backup,
+        replayed,
+        lost,
+        ..
+    } = &recovery
+    else {
+        panic!("{}", recovery);
+    };
+    assert_eq!(backup, &PathBuf::from(format!("{}.bak.2", settings.name)));
+    assert_eq!(std::fs::read_to_string(quarantined).unwrap(), "not json");
+    // The logs kept with the backups, and the current one, bring it up to
+    // date.
+    assert_eq!(*replayed, 5);
+    assert_eq!(*lost, 0);
+    assert_eq!(
+        names(&store),
+        ["Zelda", "Kirby", "Metroid", "Mario", "Luigi"]
+    );
+    assert!(store.recovery().is_some());
+    assert_eq!(
+        names(&ItemStore::new(&settings)),
+        ["Zelda", "Kirby", "Metroid", "Mario", "Luigi"]
+    );
+}
+
+#[test]
+fn test_changes_without_a_log_are_reported_lost() {
+    let mut settings = settings("lost", json!([]), 2);
+    settings.backups = 3;
+
+    let store = ItemStore::new(&settings);
+    for name in ["Zelda", "Kirby", "Metroid", "Mario", "Luigi"] {
+        store.create(name.to_string()).unwrap();
+    }
+    drop(store);
+
+    std::fs::write(&settings.name, "not json").unwrap();
+    std::fs::remove_file(format!("{}.bak.1.log", settings.name)).unwrap();
+
+    let store = ItemStore::new(&settings);
+    let recovery = store.recover().unwrap().unwrap();
+    assert!(
+        matches!(recovery, Recovery::Restored { .. }),
+        "{}",
+        recovery
+    );
+    // The changes between the backup and the lost data file are gone.
+    assert_eq!(recovery.lost(), 2);
+    assert!(
+        recovery.to_string().contains("2 items were lost"),
+        "{}",
+        recovery
+    );
+    assert_eq!(names(&store), ["Zelda", "Kirby", "Luigi"]);
+}
+
+#[test]
+fn test_corrupt_data_file_without_backup_starts_empty() {
+    let settings = settings("emptied", json!([]), 100);
+    std::fs::write(
+        &settings.name,
+        r#"[{"id": 1, "name": "Zelda"}, {"id": 1, "name": "Mario"}]"#,
+    )
+    .unwrap();
+
+    let store = ItemStore::new(&settings);
+    let recovery = store.recover().unwrap().unwrap();
+    let Recovery::Emptied {
+        reason,
+        quarantined,
+        lost,
+    } = &recovery
+    else {
+        panic!("{}", recovery);
+    };
+    assert!(reason.contains("duplicate item id 1"), "{}", reason);
+    assert!(quarantined.exists());
+    // Neither item has a revision, so no change is known to be lost.
+    assert_eq!(*lost, 0);
+    assert!(names(&store).is_empty());
+    assert_eq!(read_json(&settings)["version"], schema::VERSION);
+}
+
+#[test]
+fn test_corrupt_log_is_quarantined() {
+    let settings = settings("quarantine", json!([{"id": 1, "name": "Zelda"}]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Kirby".to_string()).unwrap();
+    drop(store);
+
+    let mut log = std::fs::read_to_string(log_path(&settings)).unwrap();
+    log.push_str("not json\n");
+    std::fs::write(log_path(&settings), log).unwrap();
+
+    let store = ItemStore::new(&settings);
+    let recovery = store.recover().unwrap().unwrap();
+    let Recovery::LogQuarantined { quarantined, .. } = &recovery else {
+        panic!("{}", recovery);
+    };
+    assert!(std::fs::read_to_string(quarantined)
+        .unwrap()
+        .ends_with("not json\n"));
+
+    // The snapshot survives; the changes only in the log do not.
+    assert_eq!(recovery.lost(), 1);
+    assert_eq!(names(&store), ["Zelda"]);
+    store.create("Metroid".to_string()).unwrap();
+    assert_eq!(names(&ItemStore::new(&settings)), ["Zelda", "Metroid"]);
+}
+
+#[test]
+fn test_healthy_store_needs_no_recovery() {
+    let settings = settings("healthy", json!([{"id": 1, "name": "Zelda"}]), 100);
+
+    let store = ItemStore::new(&settings);
+    assert!(store.recover().unwrap().is_none());
+    assert!(store.recovery().is_none());
+    assert_eq!(names(&store), ["Zelda"]);
+}
+
+#[test]
+fn test_update_keeping_the_name_keeps_it_unique() {
+    let settings = settings("same_name", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.update(1, "ZELDA".to_string()).unwrap();
+
+    let err = store.create("zelda".to_string()).unwrap_err();
+    assert!(matches!(err, StoreError::Conflict { id: 1, .. }), "{}", err);
+    let err = ItemStore::new(&settings)
+        .create("zelda".to_string())
+        .unwrap_err();
+    assert!(matches!(err, StoreError::Conflict { id: 1, .. }), "{}", err);
+}
+
+#[test]
+fn test_ids_are_never_reused() {
+    let settings = settings("ids", json!([]), 3);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    store.delete(2).unwrap();
+    store.purge(2).unwrap();
+    assert_eq!(store.create("Metroid".to_string()).unwrap().id, 3);
+
+    // The highest ID survives compaction, which has happened by now.
+    store.delete(3).unwrap();
+    store.purge(3).unwrap();
+    store.flush().unwrap();
+    assert_eq!(read_json(&settings)["last_id"], 3);
+    assert_eq!(
+        ItemStore::new(&settings)
+            .create("Mario".to_string())
+            .unwrap()
+            .id,
+        4
+    );
+}
+
+#[test]
+fn test_ids_run_out() {
+    let settings = settings("exhausted", json!([]), 100);
+    let item = Item::new(u16::MAX - 1, "Zelda".to_string());
+    std::fs::write(
+        &settings.name,
+        schema::encode(&[&item], u16::MAX - 1).unwrap(),
+    )
+    .unwrap();
+
+    let store = ItemStore::new(&settings);
+    let err = store
+        .import(vec!["Kirby".to_string(), "Metroid".to_string()])
+        .unwrap_err();
+    assert!(matches!(err, StoreError::IdsExhausted), "{}", err);
+
+    assert_eq!(store.create("Kirby".to_string()).unwrap().id, u16::MAX);
+    let err = store.create("Metroid".to_string()).unwrap_err();
+    assert!(matches!(err, StoreError::IdsExhausted), "{}", err);
+    assert_eq!(names(&store), ["Zelda", "Kirby"]);
+}
+
+#[test]
+fn test_replacing_the_items_keeps_the_highest_id() {
+    let settings = settings("replace_ids", json!([]), 100);
+
+    let store = ItemStore::new(&settings);
+    store.create("Zelda".to_string()).unwrap();
+    store.create("Kirby".to_string()).unwrap();
+    store.replace(br#"[{"id": 1, "name": "Zelda"}]"#).unwrap();
+
+    assert_eq!(store.create("Metroid".to_string()).unwrap().id, 3);
+}
+
+#[test]
+fn test_last_id_is_covered_by_the_checksum() {
+    let settings = settings("last_id_checksum", json!([]), 100);
+    ItemStore::new(&settings).migrate().unwrap();
+
+    let mut data = read_json(&settings);
+    data["last_id"] = json!(7);
+    std::fs::write(&settings.name, data.to_string()).unwrap();
+
+    let err = ItemStore::new(&settings).check().unwrap_err();
+    assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+}
+
+#[test]
+fn test_upgrade_records_the_highest_id() {
+    let items = json!([{"id": 3, "name": "Zelda"}, {"id": 7, "name": "Kirby"}]);
+    let checksum = hex::encode(Sha256::digest(items.to_string()));
+    let settings = settings(
+        "version_3_last_id",
+        json!({"version": 3, "checksum": checksum, "items": items}),
+        100,
+    );
+
+    let store = ItemStore::new(&settings);
+    store.migrate().unwrap();
+    assert_eq!(read_json(&settings)["last_id"], 7);
+    assert_eq!(store.create("Metroid".to_string()).unwrap().id, 8);
+}
+
+#[test]
+fn test_failed_revision_does_not_fail_the_write() {
+    let mut settings = settings("unrevised", json!([]), 100);
+    // A directory cannot be appended to.
+    settings.revisions_file = std::env::temp_dir()
+        .join("playasia_store_unrevised")
+        .to_string_lossy()
+        .into_owned();
+
+    let store = ItemStore::new(&settings);
+    assert_eq!(store.create("Zelda".to_string()).unwrap().id, 1);
+    store.update(1, "Zelda II".to_string()).unwrap();
+    assert_eq!(names(&store), ["Zelda II"]);
+    assert_eq!(names(&ItemStore::new(&settings)), ["Zelda II"]);
+}
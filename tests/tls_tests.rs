@@ -0,0 +1,165 @@
+use playasia::config::{Settings, TlsSettings};
+use playasia::server::create_app_with_peers;
+use playasia::tls::{certificate_subject, server_config, PeerIdentities, TlsListener};
+use poem::listener::{Acceptor, Listener, TcpListener};
+use poem::Server;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use serial_test::serial;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+struct Pki {
+    ca: Certificate,
+    ca_key: KeyPair,
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("playasia_tls_{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Playasia Test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Self { ca, ca_key, dir }
+    }
+
+    fn issue(&self, common_name: &str, client: bool) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        if client {
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        }
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        (cert, key)
+    }
+
+    fn tls_settings(&self) -> TlsSettings {
+        let (cert, key) = self.issue("localhost", false);
+        std::fs::write(self.dir.join("server.pem"), cert.pem()).unwrap();
+        std::fs::write(self.dir.join("server.key"), key.serialize_pem()).unwrap();
+
+        TlsSettings {
+            enabled: true,
+            cert: self.dir.join("server.pem").to_string_lossy().into_owned(),
+            key: self.dir.join("server.key").to_string_lossy().into_owned(),
+            client_ca: Some(self.dir.join("ca.pem").to_string_lossy().into_owned()),
+            require_client_cert: false,
+            client_scopes: vec!["items:write".to_string()],
+        }
+    }
+
+    fn client_config(&self, client: Option<(Certificate, KeyPair)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+
+        match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+}
+
+async fn start_server(settings: &Settings) -> SocketAddr {
+    let peers = PeerIdentities::new();
+    let config = server_config(&settings.tls).unwrap();
+    let acceptor = TlsListener::new(TcpListener::bind("127.0.0.1:0"), config, peers.clone())
+        .into_acceptor()
+        .await
+        .unwrap();
+    let addr = *acceptor.local_addr()[0].as_socket_addr().unwrap();
+
+    let app = create_app_with_peers(settings, peers);
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+    addr
+}
+
+async fn post_item(addr: SocketAddr, config: ClientConfig) -> String {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let connector = TlsConnector::from(Arc::new(config));
+    let mut stream = connector
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    let body = r#"{"name": "Test Item"}"#;
+    let request = format!(
+        "POST /items HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).await;
+    response
+}
+
+#[tokio::test]
+#[serial]
+async fn test_client_certificate_authenticates_writes() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let pki = Pki::new("client_cert");
+    let settings = Settings {
+        tls: pki.tls_settings(),
+        ..Settings::default()
+    };
+    let addr = start_server(&settings).await;
+
+    let client = pki.issue("billing-service", true);
+    let response = post_item(addr, pki.client_config(Some(client))).await;
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_tls_without_client_certificate_requires_token() {
+    std::fs::write("data.json", "[]").expect("Failed to reset data file.");
+
+    let pki = Pki::new("no_client_cert");
+    let settings = Settings {
+        tls: pki.tls_settings(),
+        ..Settings::default()
+    };
+    let addr = start_server(&settings).await;
+
+    let response = post_item(addr, pki.client_config(None)).await;
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+}
+
+#[test]
+fn test_certificate_subject_uses_common_name() {
+    let pki = Pki::new("subject");
+    let (cert, _) = pki.issue("billing-service", true);
+
+    assert_eq!(
+        certificate_subject(cert.der()),
+        Some("billing-service".to_string())
+    );
+}